@@ -1,34 +1,66 @@
 //! An arboric::config::Builder allows for a fluent interface for
 //! building arboric::Configuration
 
-use super::{JwtSigningKeySource, ListenerConfig};
+use super::{
+    BatchConfig, ErrorVerbosity, JwksSource, JwtSigningKey, JwtSigningKeySource, ListenerConfig,
+    SchemaSource, TlsConfig, UpstreamTlsConfig,
+};
 use crate::abac::Policy;
 use crate::arboric::influxdb;
+use crate::arboric::metering::Sink;
+use crate::arboric::metrics::Metrics;
 use hyper::Uri;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
 
 /// A ListenerBuilder implements the fluent-syntax builder for
 /// [arboric::Configuration](arboric::Configuration)
 pub struct ListenerBuilder {
     bind_address: IpAddr,
     port: u16,
+    tls: Option<TlsConfig>,
     proxy_uri: Option<Uri>,
-    jwt_signing_key_source: Option<JwtSigningKeySource>,
+    upstream_tls: Option<UpstreamTlsConfig>,
+    jwt_signing_keys: Vec<JwtSigningKey>,
+    jwt_validation: crate::jwt::Validation,
+    anonymous: bool,
+    error_verbosity: ErrorVerbosity,
+    batch: BatchConfig,
+    schema: Option<SchemaSource>,
     policies: Vec<Policy>,
-    influx_db_backend: Option<influxdb::Backend>,
+    require_allow: bool,
+    sinks: Vec<Arc<dyn Sink>>,
+    log_claims: Vec<String>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl Default for ListenerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ListenerBuilder {
-    // Constructs a new ListenerBuilder with no JWT signing key source,
+    // Constructs a new ListenerBuilder with no JWT signing keys,
     // an empty Policy list, and no query logging
     pub fn new() -> Self {
         ListenerBuilder {
             bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
             port: 0,
+            tls: None,
             proxy_uri: None,
-            jwt_signing_key_source: None,
+            upstream_tls: None,
+            jwt_signing_keys: Vec::new(),
+            jwt_validation: crate::jwt::Validation::default(),
+            anonymous: false,
+            error_verbosity: ErrorVerbosity::default(),
+            batch: BatchConfig::default(),
+            schema: None,
             policies: Vec::new(),
-            influx_db_backend: None,
+            require_allow: false,
+            sinks: Vec::new(),
+            log_claims: Vec::new(),
+            metrics: None,
         }
     }
 
@@ -56,6 +88,12 @@ impl ListenerBuilder {
         self
     }
 
+    /// Configure this `Listener` to terminate TLS
+    pub fn tls(&mut self, tls_config: TlsConfig) -> &mut Self {
+        self.tls = Some(tls_config);
+        self
+    }
+
     pub fn proxy<I>(mut self, i: I) -> Self
     where
         I: Into<Uri>,
@@ -64,15 +102,112 @@ impl ListenerBuilder {
         self
     }
 
-    /// Configure this `Listener` with a hexadecimal JWT signing key from then environment
-    pub fn jwt_from_env_hex<S: Into<String>>(&mut self, key: S) -> &mut Self {
-        self.jwt_signing_key_source = Some(JwtSigningKeySource::hex_from_env(key.into()));
+    /// Configure how this `Listener` connects to an `https://` upstream API
+    pub fn upstream_tls(&mut self, upstream_tls: UpstreamTlsConfig) -> &mut Self {
+        self.upstream_tls = Some(upstream_tls);
+        self
+    }
+
+    /// Add a JWT signing key, optionally tagged with the key id (`kid`) of the tokens
+    /// it signs. A `Listener` accepts tokens signed by any of its keys.
+    pub fn add_jwt_signing_key(
+        &mut self,
+        kid: Option<String>,
+        source: JwtSigningKeySource,
+    ) -> &mut Self {
+        self.jwt_signing_key(JwtSigningKey::new(kid, source))
+    }
+
+    /// Add a [JwtSigningKey](arboric::config::JwtSigningKey), e.g. to use an HMAC
+    /// algorithm other than HS256
+    pub fn jwt_signing_key(&mut self, signing_key: JwtSigningKey) -> &mut Self {
+        self.jwt_signing_keys.push(signing_key);
         self
     }
 
-    /// Configure this `Listener` with a JWT signing key from a file
+    /// Add a hexadecimal JWT signing key from then environment to this `Listener`
+    pub fn jwt_from_env_hex<S: Into<String>>(&mut self, key: S) -> &mut Self {
+        self.add_jwt_signing_key(None, JwtSigningKeySource::hex_from_env(key.into()))
+    }
+
+    /// Add a JWT signing key from a file to this `Listener`
     pub fn jwt_from_file<S: Into<String>>(&mut self, filename: S) -> &mut Self {
-        self.jwt_signing_key_source = Some(JwtSigningKeySource::from_file(filename.into()));
+        self.add_jwt_signing_key(None, JwtSigningKeySource::from_file(filename.into()))
+    }
+
+    /// Add a PEM encoded public key to verify JWTs signed with the given (RSA, ECDSA or EdDSA)
+    /// algorithm to this `Listener`
+    pub fn jwt_public_key<S: Into<String>>(
+        &mut self,
+        filename: S,
+        algorithm: crate::jwt::Algorithm,
+    ) -> &mut Self {
+        self.add_jwt_signing_key(
+            None,
+            JwtSigningKeySource::public_key(filename.into(), algorithm),
+        )
+    }
+
+    /// Add the keys in a JWKS file to this `Listener`
+    pub fn jwks_from_file<S: Into<String>>(&mut self, filename: S) -> &mut Self {
+        self.add_jwt_signing_key(
+            None,
+            JwtSigningKeySource::Jwks(JwksSource::FromFile {
+                filename: filename.into(),
+            }),
+        )
+    }
+
+    /// Add the keys in a JWKS fetched from the given URI, and refreshed every
+    /// `refresh_interval`, to this `Listener`
+    pub fn jwks_from_uri<S: Into<String>>(
+        &mut self,
+        uri: S,
+        refresh_interval: std::time::Duration,
+    ) -> &mut Self {
+        self.add_jwt_signing_key(
+            None,
+            JwtSigningKeySource::Jwks(JwksSource::FromUri {
+                uri: uri.into(),
+                refresh_interval,
+                timeout: crate::jwt::jwks::DEFAULT_FETCH_TIMEOUT,
+            }),
+        )
+    }
+
+    /// Configure the issuers, audiences, clock skew and maximum token age this `Listener`
+    /// accepts in JWT claims
+    pub fn jwt_validation(&mut self, jwt_validation: crate::jwt::Validation) -> &mut Self {
+        self.jwt_validation = jwt_validation;
+        self
+    }
+
+    /// Allow requests without credentials. These are evaluated against the policies
+    /// with no claims, rather than refused outright. Invalid tokens are still refused.
+    pub fn anonymous(&mut self, anonymous: bool) -> &mut Self {
+        self.anonymous = anonymous;
+        self
+    }
+
+    /// Configure how much the error responses to refused requests say about why
+    pub fn error_verbosity(&mut self, error_verbosity: ErrorVerbosity) -> &mut Self {
+        self.error_verbosity = error_verbosity;
+        self
+    }
+
+    /// Configure the largest batch of requests this `Listener` accepts, and what it
+    /// does when some of a batch's requests are refused
+    pub fn batch(&mut self, batch: BatchConfig) -> &mut Self {
+        self.batch = batch;
+        self
+    }
+
+    /// Load the back-end API's schema from the given SDL file, so that policies can
+    /// use `type:` patterns
+    pub fn schema_from_file<S: Into<String>>(&mut self, filename: S) -> &mut Self {
+        self.schema = Some(SchemaSource::FromFile {
+            filename: filename.into(),
+        });
         self
     }
 
@@ -81,10 +216,38 @@ impl ListenerBuilder {
         self
     }
 
-    pub fn log_to_influx_db(&mut self, uri: &String, database: &String) -> &mut Self {
-        self.influx_db_backend = Some(influxdb::Backend {
-            config: influxdb::Config::new(uri.clone(), database.clone()),
-        });
+    /// Only allow operations whose every field is allowed by an `allow` rule of a
+    /// policy that applies, instead of any operation its `deny` rules don't match
+    pub fn require_allow(&mut self, require_allow: bool) -> &mut Self {
+        self.require_allow = require_allow;
+        self
+    }
+
+    pub fn log_to_influx_db(&mut self, uri: &str, database: &str) -> &mut Self {
+        self.sink(Arc::new(influxdb::Backend::new(influxdb::Config::new(
+            uri.to_string(),
+            database.to_string(),
+        ))))
+    }
+
+    /// Adds a metering sink, that's sent an event for each request this listener
+    /// handles or refuses. A listener can log to any number of sinks
+    pub fn sink(&mut self, sink: Arc<dyn Sink>) -> &mut Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    /// Tags each request that's logged with the value of the given claim, if the
+    /// caller has it, e.g. `"tenant_id"` for per-tenant usage reporting
+    pub fn log_claim<S: Into<String>>(&mut self, claim: S) -> &mut Self {
+        self.log_claims.push(claim.into());
+        self
+    }
+
+    /// Records this listener's metrics in the given registry, which may be shared with
+    /// other listeners. The registry doesn't need to be added as a sink as well
+    pub fn metrics(&mut self, metrics: Arc<Metrics>) -> &mut Self {
+        self.metrics = Some(metrics);
         self
     }
 
@@ -92,10 +255,23 @@ impl ListenerBuilder {
         ListenerConfig {
             listener_address: SocketAddr::new(self.bind_address, self.port),
             listener_path: None,
+            tls: self.tls,
             api_uri: self.proxy_uri.unwrap(),
-            jwt_signing_key_source: self.jwt_signing_key_source,
-            pdp: crate::abac::PDP::with_policies(self.policies),
-            influx_db_backend: self.influx_db_backend,
+            upstream_tls: self.upstream_tls,
+            jwt_signing_keys: self.jwt_signing_keys,
+            jwt_validation: self.jwt_validation,
+            anonymous: self.anonymous,
+            error_verbosity: self.error_verbosity,
+            batch: self.batch,
+            schema: self.schema,
+            pdp: {
+                let mut pdp = crate::abac::PDP::with_policies(self.policies);
+                pdp.require_allow(self.require_allow);
+                pdp
+            },
+            sinks: self.sinks,
+            log_claims: self.log_claims,
+            metrics: self.metrics,
         }
     }
 }
@@ -5,6 +5,7 @@ use crate::abac::PDP;
 use http::Uri;
 use std::env;
 use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 
 mod listener_builder;
 pub use listener_builder::ListenerBuilder;
@@ -18,12 +19,19 @@ pub struct Configuration {
     pub listeners: Vec<ListenerConfig>,
 }
 
+impl Default for Configuration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Configuration {
     // Creates a new, empty [Configuration](arboric::config::Configuration)
     pub fn new() -> Configuration {
         Configuration {
             arboric: ArboricConfiguration {
                 loggers: Vec::new(),
+                metrics: None,
             },
             listeners: Vec::new(),
         }
@@ -45,6 +53,25 @@ impl Configuration {
 #[derive(Debug)]
 pub struct ArboricConfiguration {
     pub loggers: Vec<Logger>,
+    pub metrics: Option<MetricsConfig>,
+}
+
+/// A [MetricsConfig](arboric::config::MetricsConfig) is where to serve Prometheus
+/// metrics from, and the registry the listeners that log to `prometheus` share
+#[derive(Debug, Clone)]
+pub struct MetricsConfig {
+    pub listener_address: SocketAddr,
+    pub metrics: Arc<super::metrics::Metrics>,
+}
+
+impl MetricsConfig {
+    /// Constructs a MetricsConfig with an empty registry
+    pub fn new(listener_address: SocketAddr) -> MetricsConfig {
+        MetricsConfig {
+            listener_address,
+            metrics: Arc::new(super::metrics::Metrics::new()),
+        }
+    }
 }
 
 /// A Logger configuration. May be `Console` or `File`
@@ -59,17 +86,33 @@ pub enum Logger {
 /// * an inbound endpoint, comprising:
 ///   * a 'bind' IP address
 ///   * an optional 'path' or prefix, e.g. `"/graphql"`
-/// * a back-end API URL
-/// * an optional InfluxDB backend configuration
+///   * an optional TLS configuration
+/// * a back-end API URL, and an optional upstream TLS configuration
+/// * zero or more JWT signing keys, and the validation of JWT claims
+/// * whether to allow anonymous requests, which are evaluated with no claims
+/// * how much to say about why a request was refused
+/// * how to handle batches of requests
+/// * an optional schema of the back-end API, for `type:` patterns
+/// * the metering sinks to log requests to (e.g. InfluxDB), and which claims to log
+/// * an optional Prometheus metrics registry
 /// * an `arboric::abac::PDP` or set of ABAC policies
 #[derive(Debug, Clone)]
 pub struct ListenerConfig {
     pub listener_address: SocketAddr,
     pub listener_path: Option<String>,
+    pub tls: Option<TlsConfig>,
     pub api_uri: Uri,
-    pub jwt_signing_key_source: Option<JwtSigningKeySource>,
+    pub upstream_tls: Option<UpstreamTlsConfig>,
+    pub jwt_signing_keys: Vec<JwtSigningKey>,
+    pub jwt_validation: crate::jwt::Validation,
+    pub anonymous: bool,
+    pub error_verbosity: ErrorVerbosity,
+    pub batch: BatchConfig,
+    pub schema: Option<SchemaSource>,
     pub pdp: crate::abac::PDP,
-    pub influx_db_backend: Option<super::influxdb::Backend>,
+    pub sinks: Vec<Arc<dyn super::metering::Sink>>,
+    pub log_claims: Vec<String>,
+    pub metrics: Option<Arc<super::metrics::Metrics>>,
 }
 
 impl ListenerConfig {
@@ -79,14 +122,188 @@ impl ListenerConfig {
         ListenerConfig {
             listener_address: SocketAddr::new(ip_addr, port),
             listener_path: None,
+            tls: None,
             api_uri: api_uri.clone(),
-            jwt_signing_key_source: None,
+            upstream_tls: None,
+            jwt_signing_keys: Vec::new(),
+            jwt_validation: crate::jwt::Validation::default(),
+            anonymous: false,
+            error_verbosity: ErrorVerbosity::default(),
+            batch: BatchConfig::default(),
+            schema: None,
             pdp: PDP::default(),
-            influx_db_backend: None,
+            sinks: Vec::new(),
+            log_claims: Vec::new(),
+            metrics: None,
+        }
+    }
+}
+
+/// An [ErrorVerbosity](arboric::config::ErrorVerbosity) is how much the error responses
+/// to refused requests say about why they were refused
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ErrorVerbosity {
+    /// Only the HTTP status and an error code
+    Minimal,
+    /// The reason, e.g. "Token has expired"
+    #[default]
+    Normal,
+    /// The reason, and any further details, e.g. where parsing the query failed
+    Verbose,
+}
+
+impl std::str::FromStr for ErrorVerbosity {
+    type Err = crate::ArboricError;
+
+    fn from_str(s: &str) -> crate::Result<ErrorVerbosity> {
+        match s {
+            "minimal" => Ok(ErrorVerbosity::Minimal),
+            "normal" => Ok(ErrorVerbosity::Normal),
+            "verbose" => Ok(ErrorVerbosity::Verbose),
+            _ => Err(crate::ArboricError::general(format!(
+                r#"Unsupported error verbosity "{}""#,
+                s
+            ))),
+        }
+    }
+}
+
+/// A [BatchConfig](arboric::config::BatchConfig) configures how a listener handles
+/// batches: JSON arrays of GraphQL requests, each of which is authorized on its own
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatchConfig {
+    /// The most requests a batch can have. Larger batches are refused.
+    pub max_size: usize,
+    /// What to do when the policies don't allow some of the requests in a batch
+    pub on_denied: BatchDenial,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        BatchConfig {
+            max_size: 10,
+            on_denied: BatchDenial::RejectBatch,
+        }
+    }
+}
+
+/// What to do with a batch when some of its requests are refused
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BatchDenial {
+    /// Refuse the whole batch
+    RejectBatch,
+    /// Forward the rest of the batch, and give the refused requests an error as their result
+    DenyEntries,
+}
+
+impl std::str::FromStr for BatchDenial {
+    type Err = crate::ArboricError;
+
+    fn from_str(s: &str) -> crate::Result<BatchDenial> {
+        match s {
+            "reject_batch" => Ok(BatchDenial::RejectBatch),
+            "deny_entries" => Ok(BatchDenial::DenyEntries),
+            _ => Err(crate::ArboricError::general(format!(
+                r#"Unsupported batch on_denied "{}""#,
+                s
+            ))),
+        }
+    }
+}
+
+/// A [SchemaSource](arboric::config::SchemaSource) is where to read the back-end API's
+/// schema (SDL) from
+#[derive(Debug, Clone, PartialEq)]
+pub enum SchemaSource {
+    FromFile { filename: String },
+}
+
+/// A [TlsConfig](arboric::config::TlsConfig) enables TLS termination on a listener
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// The PEM file containing the server certificate, followed by any intermediate certificates
+    pub certificate_chain: String,
+    /// The PEM file containing the server private key
+    pub private_key: String,
+    /// Whether to offer HTTP/2 to clients (via ALPN)
+    pub http2: bool,
+    /// Whether to request (or require) client certificates
+    pub client_auth: Option<ClientAuthConfig>,
+}
+
+impl TlsConfig {
+    pub fn new<S: Into<String>>(certificate_chain: S, private_key: S) -> TlsConfig {
+        TlsConfig {
+            certificate_chain: certificate_chain.into(),
+            private_key: private_key.into(),
+            http2: false,
+            client_auth: None,
+        }
+    }
+}
+
+/// A [ClientAuthConfig](arboric::config::ClientAuthConfig) makes a TLS listener ask
+/// clients for a certificate, verified against the given CA bundle. The fields of a
+/// verified client certificate become claims, so ABAC policies can match callers
+/// that authenticate with a certificate instead of a JWT.
+#[derive(Debug, Clone)]
+pub struct ClientAuthConfig {
+    /// The PEM file containing the CA certificates that client certificates must chain to
+    pub ca_bundle: String,
+    /// If `true`, connections without a valid client certificate are refused
+    pub required: bool,
+    pub claims: ClientCertificateClaims,
+}
+
+impl ClientAuthConfig {
+    pub fn new<S: Into<String>>(ca_bundle: S) -> ClientAuthConfig {
+        ClientAuthConfig {
+            ca_bundle: ca_bundle.into(),
+            required: true,
+            claims: ClientCertificateClaims::default(),
         }
     }
 }
 
+/// The names of the claims that client certificate fields map to. The defaults
+/// don't collide with registered JWT claims such as `sub` and `iss`, so policies
+/// written for tokens don't match certificates by accident.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientCertificateClaims {
+    /// The claim for the subject distinguished name, by default `"cert_sub"`
+    pub subject: String,
+    /// The claim for the subject alternative names, as an array, by default
+    /// `"cert_sans"`
+    pub subject_alt_names: String,
+    /// The claim for the issuer distinguished name, by default `"cert_iss"`
+    pub issuer: String,
+}
+
+impl Default for ClientCertificateClaims {
+    fn default() -> Self {
+        ClientCertificateClaims {
+            subject: "cert_sub".into(),
+            subject_alt_names: "cert_sans".into(),
+            issuer: "cert_iss".into(),
+        }
+    }
+}
+
+/// An [UpstreamTlsConfig](arboric::config::UpstreamTlsConfig) configures how a listener
+/// connects to an `https://` upstream API:
+///
+/// * an optional CA bundle to trust, in addition to the system's trusted CAs
+/// * an optional client certificate and private key, for two-way TLS
+/// * an optional server name to use for SNI and certificate verification, instead
+///   of the host in the upstream URI
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamTlsConfig {
+    pub ca_bundle: Option<String>,
+    pub client_certificate: Option<String>,
+    pub client_key: Option<String>,
+    pub server_name: Option<String>,
+}
+
 /// A [KeyEncoding](arboric::config::KeyEncoding) just tells us whether the value is encoded as
 /// hex or base64
 #[derive(Debug, Clone)]
@@ -96,6 +313,40 @@ pub enum KeyEncoding {
     Base64,
 }
 
+/// A [JwtSigningKey](arboric::config::JwtSigningKey) is a
+/// [JwtSigningKeySource](arboric::config::JwtSigningKeySource), optionally tagged
+/// with the key id (`kid`) of the tokens it signs
+#[derive(Debug, Clone)]
+pub struct JwtSigningKey {
+    pub kid: Option<String>,
+    pub source: JwtSigningKeySource,
+    /// The HMAC algorithm a secret signs with, HS256 unless configured otherwise.
+    /// Public keys and JWKS keys have their own algorithms, so ignore this.
+    pub algorithm: crate::jwt::Algorithm,
+}
+
+impl JwtSigningKey {
+    pub fn new(kid: Option<String>, source: JwtSigningKeySource) -> JwtSigningKey {
+        JwtSigningKey {
+            kid,
+            source,
+            algorithm: crate::jwt::Algorithm::HS256,
+        }
+    }
+
+    /// Use the given HMAC algorithm (HS256, HS384 or HS512) for a secret
+    pub fn algorithm(mut self, algorithm: crate::jwt::Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+}
+
+impl From<JwtSigningKeySource> for JwtSigningKey {
+    fn from(source: JwtSigningKeySource) -> Self {
+        JwtSigningKey::new(None, source)
+    }
+}
+
 /// A [JwtSigningKeySource](arboric::config::JwtSigningKeySource) defines
 /// where and how to retrieve the signing key used to validate JWT bearer tokens.
 /// It can be one of
@@ -109,6 +360,10 @@ pub enum KeyEncoding {
 /// * the string value or file contents taken as 'raw' bytes,
 /// * a hex encoded value, or
 /// * a base64 encoded value
+///
+/// The above are all HMAC secrets, HS256 unless the
+/// [JwtSigningKey](arboric::config::JwtSigningKey) says otherwise. For asymmetric algorithms, the key source
+/// can instead be a PEM encoded `PublicKey`, or a `Jwks` (JSON Web Key Set)
 #[derive(Debug, Clone)]
 pub enum JwtSigningKeySource {
     Value(String, KeyEncoding),
@@ -120,6 +375,26 @@ pub enum JwtSigningKeySource {
         filename: String,
         encoding: KeyEncoding,
     },
+    PublicKey {
+        filename: String,
+        algorithm: crate::jwt::Algorithm,
+    },
+    Jwks(JwksSource),
+}
+
+/// A [JwksSource](arboric::config::JwksSource) is where to read a JSON Web Key Set from
+#[derive(Debug, Clone)]
+pub enum JwksSource {
+    FromFile {
+        filename: String,
+    },
+    /// The JWKS at the URI is fetched once at startup, then every `refresh_interval`.
+    /// A fetch that takes longer than `timeout` fails.
+    FromUri {
+        uri: String,
+        refresh_interval: std::time::Duration,
+        timeout: std::time::Duration,
+    },
 }
 
 impl JwtSigningKeySource {
@@ -152,10 +427,17 @@ impl JwtSigningKeySource {
         }
     }
 
+    pub fn public_key(filename: String, algorithm: crate::jwt::Algorithm) -> JwtSigningKeySource {
+        JwtSigningKeySource::PublicKey {
+            filename,
+            algorithm,
+        }
+    }
+
     pub fn get_secret_key_bytes(&self) -> crate::Result<Vec<u8>> {
         match self {
             JwtSigningKeySource::Value(secret, encoding) => match encoding {
-                KeyEncoding::Hex => Ok(hex::decode(&secret)?),
+                KeyEncoding::Hex => Ok(hex::decode(secret)?),
                 KeyEncoding::Base64 => Ok(base64::decode(&secret)?),
                 x => Err(crate::ArboricError::general(format!(
                     "Not yet implemented: {:?}!",
@@ -178,9 +460,13 @@ impl JwtSigningKeySource {
             },
             JwtSigningKeySource::FromFile { filename, encoding } => match encoding {
                 KeyEncoding::Bytes => Ok(std::fs::read(filename)?),
-                KeyEncoding::Hex => read_file_as_hex(&filename),
-                KeyEncoding::Base64 => read_file_as_base64(&filename),
+                KeyEncoding::Hex => read_file_as_hex(filename),
+                KeyEncoding::Base64 => read_file_as_base64(filename),
             },
+            x => Err(crate::ArboricError::general(format!(
+                "{:?} is not a secret key!",
+                x
+            ))),
         }
     }
 }
@@ -214,7 +500,7 @@ mod tests {
                 .proxy("http://localhost:3000/graphql".parse::<Uri>().unwrap())
         });
         assert!(!configuration.listeners.is_empty());
-        assert_eq!(1, configuration.listeners.iter().count());
+        assert_eq!(1, configuration.listeners.len());
         assert_eq!(
             SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 4000)),
             configuration.listeners.first().unwrap().listener_address
@@ -11,27 +11,106 @@
 //!     file:
 //!       location: /var/log/arboric.log
 //!       level: debug
+//!   # Serve Prometheus metrics at http://localhost:9090/metrics, for the listeners
+//!   # that log to `prometheus`
+//!   metrics:
+//!     bind: localhost
+//!     port: 9090
 //! listeners:
 //! - bind: localhost
 //!   port: 4000
-//!   proxy: http://localhost:3001/graphql
+//!   tls:
+//!     certificate_chain: /etc/arboric/tls/fullchain.pem
+//!     private_key: /etc/arboric/tls/privkey.pem
+//!     http2: true
+//!     client_auth:
+//!       ca_bundle: /etc/arboric/tls/clients-ca.pem
+//!       required: false
+//!       claims:
+//!         subject: cert_sub
+//!         subject_alt_names: cert_sans
+//!         issuer: cert_iss
+//!   proxy: https://api.internal:8443/graphql
+//!   upstream_tls:
+//!     ca_bundle: /etc/arboric/tls/upstream-ca.pem
+//!     client_certificate: /etc/arboric/tls/client.pem
+//!     client_key: /etc/arboric/tls/client.key
+//!     server_name: api.internal
 //!   jwt_signing_key:
 //!     from_env:
 //!       key: SECRET_KEY_BASE
 //!       encoding: hex
+//!       # algorithm: HS512 # HS256 unless given
+//!     # or, for RS256/ES256/EdDSA tokens, either
+//!     # public_key:
+//!     #   name: /etc/arboric/jwt.pem
+//!     #   algorithm: RS256
+//!     # or
+//!     # jwks:
+//!     #   uri: https://login.example.com/.well-known/jwks.json
+//!     #   refresh_interval: 3600 # seconds
+//!     #   timeout: 10 # seconds
+//!   # Several keys can be given at once, e.g. to rotate them without downtime.
+//!   # Key files are reloaded when they change.
+//!   # jwt_signing_keys:
+//!   # - kid: "2019-10"
+//!   #   from_file:
+//!   #     name: /etc/arboric/secret_key_2019_10
+//!   # - kid: "2019-07"
+//!   #   from_file:
+//!   #     name: /etc/arboric/secret_key_2019_07
+//!   jwt_validation:
+//!     issuers:
+//!     - https://login.example.com/
+//!     audiences:
+//!     - arboric
+//!     leeway: 30 # seconds
+//!     max_age: 86400 # seconds
+//!   # Evaluate requests without credentials against the policies, with no claims
+//!   anonymous: true
+//!   # Only allow operations whose every field an `allow` rule allows, rather than
+//!   # any operation no `deny` rule matches
+//!   require_allow: true
+//!   # How much error responses say about why a request was refused:
+//!   # minimal, normal (the default) or verbose
+//!   error_verbosity: normal
+//!   # The most requests a batch (a JSON array of requests) can have, and whether to
+//!   # refuse the whole batch (reject_batch, the default) or only the requests the
+//!   # policies don't allow (deny_entries)
+//!   batch:
+//!     max_size: 10
+//!     on_denied: reject_batch
+//!   # The back-end API's schema (SDL), needed for type patterns like "type:User{email}"
+//!   schema:
+//!     from_file: /etc/arboric/schema.graphql
+//!   # Send metering events to any number of sinks
 //!   log_to:
-//!     influx_db:
+//!   - influx_db:
 //!       uri: https://localhost:8086
 //!       database: arboric
+//!   - file:
+//!       location: /var/log/arboric-usage.log
+//!   - stdout
+//!   - prometheus
+//!   # Tag each logged request with these claims, if the caller has them
+//!   log_claims:
+//!   - sub
+//!   - tenant_id
 //! ```
 
 use crate::abac;
 use crate::arboric::graphql;
+use crate::arboric::metering::JsonLines;
 use crate::arboric::ArboricError;
+use crate::config::{
+    BatchConfig, BatchDenial, ClientAuthConfig, ErrorVerbosity, JwksSource, JwtSigningKeySource,
+    MetricsConfig, TlsConfig, UpstreamTlsConfig,
+};
 use crate::Configuration;
 use http::Uri;
 use log::trace;
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 
 /// Read the Configuration from the specified YAML file
 pub fn read_yaml_configuration(filename: &str) -> crate::Result<crate::Configuration> {
@@ -51,12 +130,10 @@ pub fn read_yaml_configuration(filename: &str) -> crate::Result<crate::Configura
 }
 
 fn read_yaml_config(f: std::fs::File) -> crate::Result<crate::Configuration> {
-    use crate::abac::MatchAttribute;
-
     let yaml_config: YamlConfig = serde_yaml::from_reader(f)?;
 
     let mut config = Configuration::new();
-    let ref mut loggers = config.arboric.loggers;
+    let loggers = &mut config.arboric.loggers;
 
     let arboric = yaml_config.arboric;
     if let Some(console) = arboric.log.console {
@@ -72,8 +149,27 @@ fn read_yaml_config(f: std::fs::File) -> crate::Result<crate::Configuration> {
         loggers.push(file_logger);
     }
 
+    if let Some(metrics) = arboric.metrics {
+        let ip_addr = if metrics.bind == "localhost" {
+            std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST)
+        } else {
+            metrics.bind.parse::<std::net::IpAddr>().unwrap()
+        };
+        config.arboric.metrics = Some(MetricsConfig::new(std::net::SocketAddr::new(
+            ip_addr,
+            metrics.port,
+        )));
+    }
+
+    let metrics = config
+        .arboric
+        .metrics
+        .as_ref()
+        .map(|metrics_config| metrics_config.metrics.clone());
+
     if let Some(listeners) = yaml_config.listeners {
         for listener_config in listeners.iter() {
+            let policies = listener_policies(listener_config)?;
             config.listener(|mut listener| {
                 listener = if listener_config.bind == "localhost" {
                     listener.localhost()
@@ -85,76 +181,142 @@ fn read_yaml_config(f: std::fs::File) -> crate::Result<crate::Configuration> {
                     .port(listener_config.port)
                     .proxy(listener_config.proxy.parse::<Uri>().unwrap());
 
-                match listener_config.jwt_signing_key {
-                    JwtSigningKey::FromEnv { ref from_env } => match &from_env.encoding {
-                        Some(encoding) => {
-                            if encoding == "hex" {
-                                listener.jwt_from_env_hex(&from_env.key);
-                            } else {
-                                panic!(r#"Unsupported encoding "{}" "#, encoding);
+                if let Some(ref tls) = listener_config.tls {
+                    let mut tls_config =
+                        TlsConfig::new(tls.certificate_chain.as_str(), tls.private_key.as_str());
+                    tls_config.http2 = tls.http2.unwrap_or(false);
+                    if let Some(ref client_auth) = tls.client_auth {
+                        let mut client_auth_config =
+                            ClientAuthConfig::new(client_auth.ca_bundle.as_str());
+                        client_auth_config.required = client_auth.required.unwrap_or(true);
+                        if let Some(ref claims) = client_auth.claims {
+                            let mapping = &mut client_auth_config.claims;
+                            if let Some(ref subject) = claims.subject {
+                                mapping.subject = subject.clone();
                             }
-                        }
-                        None => (),
-                    },
-                    JwtSigningKey::FromFile { ref from_file } => {
-                        trace!("from_file => {:?}", &from_file);
-                        match &from_file.encoding {
-                            Some(encoding) => {
-                                panic!(r#"Unsupported encoding "{}" "#, encoding);
+                            if let Some(ref subject_alt_names) = claims.subject_alt_names {
+                                mapping.subject_alt_names = subject_alt_names.clone();
                             }
-                            None => {
-                                listener.jwt_from_file(&from_file.name);
+                            if let Some(ref issuer) = claims.issuer {
+                                mapping.issuer = issuer.clone();
                             }
                         }
+                        tls_config.client_auth = Some(client_auth_config);
                     }
+                    listener.tls(tls_config);
                 }
 
-                if let Some(ref log_to) = listener_config.log_to {
-                    if let Some(ref influx_db) = log_to.influx_db {
-                        listener.log_to_influx_db(&influx_db.uri, &influx_db.database);
+                if let Some(ref upstream_tls) = listener_config.upstream_tls {
+                    listener.upstream_tls(UpstreamTlsConfig {
+                        ca_bundle: upstream_tls.ca_bundle.clone(),
+                        client_certificate: upstream_tls.client_certificate.clone(),
+                        client_key: upstream_tls.client_key.clone(),
+                        server_name: upstream_tls.server_name.clone(),
+                    });
+                }
+
+                if let Some(ref jwt_signing_key) = listener_config.jwt_signing_key {
+                    if let Some(source) = jwt_signing_key_source(jwt_signing_key) {
+                        listener.jwt_signing_key(
+                            crate::config::JwtSigningKey::new(None, source)
+                                .algorithm(hmac_algorithm(jwt_signing_key)),
+                        );
                     }
                 }
-                if let Some(policies) = listener_config.policies.as_ref() {
-                    for policy_def in policies.iter() {
-                        let mut policy = abac::Policy::new();
-                        match &policy_def.when {
-                            Some(ref vec) => {
-                                for when in vec.iter() {
-                                    let match_attribute: MatchAttribute = match when {
-                                        When::ClaimIsPresent(w) => {
-                                            MatchAttribute::claim_present(&w.claim_is_present)
-                                        }
-                                        When::ClaimEquals(w) => {
-                                            MatchAttribute::claim_equals(&w.claim, &w.equals)
-                                        }
-                                        When::ClaimIncludes(w) => {
-                                            MatchAttribute::claim_includes(&w.claim, &w.includes)
-                                        }
-                                    };
-                                    policy.add_match_attribute(match_attribute);
-                                }
-                            }
-                            None => {
-                                policy.add_match_attribute(MatchAttribute::Any);
-                            }
+                if let Some(ref jwt_signing_keys) = listener_config.jwt_signing_keys {
+                    for signing_key in jwt_signing_keys.iter() {
+                        if let Some(source) = jwt_signing_key_source(&signing_key.key) {
+                            listener.jwt_signing_key(
+                                crate::config::JwtSigningKey::new(signing_key.kid.clone(), source)
+                                    .algorithm(hmac_algorithm(&signing_key.key)),
+                            );
                         }
+                    }
+                }
 
-                        if let Some(ref allows) = policy_def.allow {
-                            for pattern in allows.iter().map(&pattern_def_to_graphql_pattern) {
-                                trace!("allow: {:?}", pattern);
-                                policy.allow(pattern);
-                            }
+                if let Some(ref jwt_validation) = listener_config.jwt_validation {
+                    listener.jwt_validation(crate::jwt::Validation {
+                        issuers: jwt_validation.issuers.clone().unwrap_or_default(),
+                        audiences: jwt_validation.audiences.clone().unwrap_or_default(),
+                        leeway: std::time::Duration::from_secs(jwt_validation.leeway.unwrap_or(0)),
+                        max_age: jwt_validation.max_age.map(std::time::Duration::from_secs),
+                    });
+                }
+
+                if let Some(anonymous) = listener_config.anonymous {
+                    listener.anonymous(anonymous);
+                }
+
+                if let Some(require_allow) = listener_config.require_allow {
+                    listener.require_allow(require_allow);
+                }
+
+                if let Some(ref error_verbosity) = listener_config.error_verbosity {
+                    match error_verbosity.parse::<ErrorVerbosity>() {
+                        Ok(error_verbosity) => {
+                            listener.error_verbosity(error_verbosity);
+                        }
+                        Err(err) => panic!("{}", err),
+                    }
+                }
+
+                if let Some(ref batch) = listener_config.batch {
+                    let mut batch_config = BatchConfig::default();
+                    if let Some(max_size) = batch.max_size {
+                        batch_config.max_size = max_size;
+                    }
+                    if let Some(ref on_denied) = batch.on_denied {
+                        match on_denied.parse::<BatchDenial>() {
+                            Ok(on_denied) => batch_config.on_denied = on_denied,
+                            Err(err) => panic!("{}", err),
                         }
+                    }
+                    listener.batch(batch_config);
+                }
 
-                        if let Some(ref denies) = policy_def.deny {
-                            for pattern in denies.iter().map(&pattern_def_to_graphql_pattern) {
-                                trace!("deny: {:?}", pattern);
-                                policy.deny(pattern);
+                if let Some(ref schema) = listener_config.schema {
+                    match schema {
+                        Schema::FromFile { from_file } => {
+                            listener.schema_from_file(from_file.clone());
+                        }
+                    }
+                }
+
+                if let Some(ref log_to) = listener_config.log_to {
+                    for sink in log_to.sinks().iter() {
+                        match sink {
+                            LogSink::InfluxDb(influx_db) => {
+                                listener.log_to_influx_db(&influx_db.uri, &influx_db.database);
                             }
+                            LogSink::File { location } => match JsonLines::file(location) {
+                                Ok(json_lines) => {
+                                    listener.sink(Arc::new(json_lines));
+                                }
+                                Err(err) => panic!("{}", err),
+                            },
+                            LogSink::Stdout => {
+                                listener.sink(Arc::new(JsonLines::stdout()));
+                            }
+                            LogSink::Prometheus => match metrics {
+                                Some(ref metrics) => {
+                                    listener.metrics(metrics.clone());
+                                }
+                                None => panic!(
+                                    "Listener on port {} logs to prometheus, but arboric.metrics isn't configured!",
+                                    listener_config.port
+                                ),
+                            },
                         }
-                        listener.add_policy(policy);
                     }
                 }
+                if let Some(ref log_claims) = listener_config.log_claims {
+                    for claim in log_claims.iter() {
+                        listener.log_claim(claim.as_str());
+                    }
+                }
+                for policy in policies {
+                    listener.add_policy(policy);
+                }
                 listener
             })
         }
@@ -163,11 +325,141 @@ fn read_yaml_config(f: std::fs::File) -> crate::Result<crate::Configuration> {
     Ok(config)
 }
 
-fn pattern_def_to_graphql_pattern(pattern: &Pattern) -> graphql::Pattern {
+fn jwt_signing_key_source(jwt_signing_key: &JwtSigningKey) -> Option<JwtSigningKeySource> {
+    match jwt_signing_key {
+        JwtSigningKey::FromEnv { ref from_env } => match &from_env.encoding {
+            Some(encoding) => {
+                if encoding == "hex" {
+                    Some(JwtSigningKeySource::hex_from_env(from_env.key.clone()))
+                } else {
+                    panic!(r#"Unsupported encoding "{}" "#, encoding);
+                }
+            }
+            None => None,
+        },
+        JwtSigningKey::FromFile { ref from_file } => {
+            trace!("from_file => {:?}", &from_file);
+            match &from_file.encoding {
+                Some(encoding) => {
+                    panic!(r#"Unsupported encoding "{}" "#, encoding);
+                }
+                None => Some(JwtSigningKeySource::from_file(from_file.name.clone())),
+            }
+        }
+        JwtSigningKey::PublicKey { ref public_key } => {
+            trace!("public_key => {:?}", &public_key);
+            match public_key.algorithm.parse::<crate::jwt::Algorithm>() {
+                Ok(algorithm) => Some(JwtSigningKeySource::public_key(
+                    public_key.name.clone(),
+                    algorithm,
+                )),
+                Err(err) => panic!("{}", err),
+            }
+        }
+        JwtSigningKey::Jwks { ref jwks } => {
+            trace!("jwks => {:?}", &jwks);
+            match (&jwks.uri, &jwks.file) {
+                (Some(uri), None) => {
+                    let refresh_interval = std::time::Duration::from_secs(
+                        jwks.refresh_interval
+                            .unwrap_or(DEFAULT_JWKS_REFRESH_INTERVAL_SECS),
+                    );
+                    let timeout = jwks.timeout.map_or(
+                        crate::jwt::jwks::DEFAULT_FETCH_TIMEOUT,
+                        std::time::Duration::from_secs,
+                    );
+                    Some(JwtSigningKeySource::Jwks(JwksSource::FromUri {
+                        uri: uri.clone(),
+                        refresh_interval,
+                        timeout,
+                    }))
+                }
+                (None, Some(file)) => Some(JwtSigningKeySource::Jwks(JwksSource::FromFile {
+                    filename: file.clone(),
+                })),
+                _ => panic!("jwks requires exactly one of uri or file!"),
+            }
+        }
+    }
+}
+
+/// The HMAC algorithm of a secret key, HS256 unless it gives one
+fn hmac_algorithm(jwt_signing_key: &JwtSigningKey) -> crate::jwt::Algorithm {
+    let algorithm = match jwt_signing_key {
+        JwtSigningKey::FromEnv { ref from_env } => from_env.algorithm.as_ref(),
+        JwtSigningKey::FromFile { ref from_file } => from_file.algorithm.as_ref(),
+        _ => None,
+    };
+    match algorithm.map(|algorithm| algorithm.parse::<crate::jwt::Algorithm>()) {
+        None => crate::jwt::Algorithm::HS256,
+        Some(Ok(algorithm)) if algorithm.is_hmac() => algorithm,
+        Some(Ok(algorithm)) => panic!(
+            r#"Unsupported algorithm "{}" for a secret key, expected HS256, HS384 or HS512"#,
+            algorithm
+        ),
+        Some(Err(err)) => panic!("{}", err),
+    }
+}
+
+/// The ABAC policies of the given listener, or an error if one of their patterns is
+/// invalid
+fn listener_policies(listener_config: &Listener) -> crate::Result<Vec<abac::Policy>> {
+    use crate::abac::MatchAttribute;
+
+    let mut policies = Vec::new();
+    for policy_def in listener_config.policies.iter().flatten() {
+        let mut policy = abac::Policy::new();
+        match &policy_def.when {
+            Some(ref vec) => {
+                for when in vec.iter() {
+                    let match_attribute: MatchAttribute = match when {
+                        When::ClaimIsPresent(w) => {
+                            MatchAttribute::claim_present(&w.claim_is_present)
+                        }
+                        When::ClaimEquals(w) => MatchAttribute::claim_equals(&w.claim, &w.equals),
+                        When::ClaimIncludes(w) => {
+                            MatchAttribute::claim_includes(&w.claim, &w.includes)
+                        }
+                    };
+                    policy.add_match_attribute(match_attribute);
+                }
+            }
+            None => {
+                policy.add_match_attribute(MatchAttribute::Any);
+            }
+        }
+
+        for pattern_def in policy_def.allow.iter().flatten() {
+            let pattern = pattern_def_to_graphql_pattern(pattern_def)?;
+            trace!("allow: {:?}", pattern);
+            policy.allow(pattern);
+        }
+
+        for pattern_def in policy_def.deny.iter().flatten() {
+            let pattern = pattern_def_to_graphql_pattern(pattern_def)?;
+            trace!("deny: {:?}", pattern);
+            policy.deny(pattern);
+        }
+        policies.push(policy);
+    }
+    Ok(policies)
+}
+
+fn pattern_def_to_graphql_pattern(pattern: &Pattern) -> crate::Result<graphql::Pattern> {
     match pattern {
-        Pattern::Query(def) => graphql::Pattern::query(&def.query),
-        Pattern::Mutation(def) => graphql::Pattern::mutation(&def.mutation),
-        Pattern::SomeString(ref s) => graphql::Pattern::parse(s),
+        Pattern::Query(def) => {
+            graphql::FieldPattern::parse(&def.query).map(graphql::Pattern::Query)
+        }
+        Pattern::Mutation(def) => {
+            graphql::FieldPattern::parse(&def.mutation).map(graphql::Pattern::Mutation)
+        }
+        Pattern::Subscription(def) => {
+            graphql::FieldPattern::parse(&def.subscription).map(graphql::Pattern::Subscription)
+        }
+        Pattern::Type(def) => {
+            graphql::TypePattern::parse(&def.type_pattern).map(graphql::Pattern::Type)
+        }
+        Pattern::SomeString(ref s) => graphql::Pattern::try_parse(s.as_str()),
     }
 }
 
@@ -180,6 +472,13 @@ struct YamlConfig {
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 struct Arboric {
     log: Log,
+    metrics: Option<Metrics>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Metrics {
+    bind: String,
+    port: u16,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -203,34 +502,150 @@ struct File {
 struct Listener {
     bind: String,
     port: u16,
+    tls: Option<Tls>,
     proxy: String,
-    jwt_signing_key: JwtSigningKey,
+    upstream_tls: Option<UpstreamTls>,
+    jwt_signing_key: Option<JwtSigningKey>,
+    jwt_signing_keys: Option<Vec<SigningKey>>,
+    jwt_validation: Option<JwtValidation>,
+    anonymous: Option<bool>,
+    require_allow: Option<bool>,
+    error_verbosity: Option<String>,
+    batch: Option<Batch>,
+    schema: Option<Schema>,
     log_to: Option<LogTo>,
+    log_claims: Option<Vec<String>>,
     policies: Option<Vec<Policy>>,
 }
 
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Batch {
+    max_size: Option<usize>,
+    on_denied: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Tls {
+    certificate_chain: String,
+    private_key: String,
+    http2: Option<bool>,
+    client_auth: Option<ClientAuth>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct ClientAuth {
+    ca_bundle: String,
+    required: Option<bool>,
+    claims: Option<ClientCertificateClaims>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct ClientCertificateClaims {
+    subject: Option<String>,
+    subject_alt_names: Option<String>,
+    issuer: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct UpstreamTls {
+    ca_bundle: Option<String>,
+    client_certificate: Option<String>,
+    client_key: Option<String>,
+    server_name: Option<String>,
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 #[serde(untagged)]
 enum JwtSigningKey {
     FromEnv { from_env: FromEnv },
     FromFile { from_file: FromFile },
+    PublicKey { public_key: PublicKey },
+    Jwks { jwks: Jwks },
+}
+
+/// An entry in `jwt_signing_keys`, which is a `jwt_signing_key` with an optional `kid`
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct SigningKey {
+    kid: Option<String>,
+    #[serde(flatten)]
+    key: JwtSigningKey,
+}
+
+/// How often to re-fetch a JWKS from its uri, unless configured otherwise
+const DEFAULT_JWKS_REFRESH_INTERVAL_SECS: u64 = 3600;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct PublicKey {
+    name: String,
+    algorithm: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Jwks {
+    uri: Option<String>,
+    file: Option<String>,
+    /// In seconds
+    refresh_interval: Option<u64>,
+    /// In seconds
+    timeout: Option<u64>,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 struct FromEnv {
     key: String,
     encoding: Option<String>,
+    /// HS256 (the default), HS384 or HS512
+    algorithm: Option<String>,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 struct FromFile {
     name: String,
     encoding: Option<String>,
+    /// HS256 (the default), HS384 or HS512
+    algorithm: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct JwtValidation {
+    issuers: Option<Vec<String>>,
+    audiences: Option<Vec<String>>,
+    /// In seconds
+    leeway: Option<u64>,
+    /// In seconds
+    max_age: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Schema {
+    FromFile { from_file: String },
+}
+
+/// `log_to` can be a single sink, or a list of them
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum LogTo {
+    Sinks(Vec<LogSink>),
+    Sink(LogSink),
+}
+
+impl LogTo {
+    fn sinks(&self) -> Vec<&LogSink> {
+        match self {
+            LogTo::Sinks(sinks) => sinks.iter().collect(),
+            LogTo::Sink(sink) => vec![sink],
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
-struct LogTo {
-    influx_db: Option<InfluxDbConfig>,
+#[serde(rename_all = "snake_case")]
+enum LogSink {
+    InfluxDb(InfluxDbConfig),
+    File { location: String },
+    Stdout,
+    Prometheus,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -248,6 +663,7 @@ struct Policy {
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 #[serde(untagged)]
+#[allow(clippy::enum_variant_names)]
 enum When {
     ClaimIsPresent(ClaimIsPresent),
     ClaimEquals(ClaimEquals),
@@ -270,6 +686,8 @@ struct ClaimEquals {
 enum Pattern {
     Query(QueryDef),
     Mutation(MutationDef),
+    Subscription(SubscriptionDef),
+    Type(TypeDef),
     SomeString(String),
 }
 
@@ -283,6 +701,17 @@ struct MutationDef {
     mutation: String,
 }
 
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct SubscriptionDef {
+    subscription: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct TypeDef {
+    #[serde(rename = "type")]
+    type_pattern: String,
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 struct ClaimIncludes {
     claim: String,
@@ -354,6 +783,7 @@ allow:
 - query: hero
 - mutation: createHero
 - "*"
+- subscription: heroCreated
 "#;
         let policy: Policy = serde_yaml::from_str(s).unwrap();
         let allow = policy.allow.unwrap();
@@ -361,7 +791,7 @@ allow:
             Pattern::Query(QueryDef {
                 query: String::from("hero")
             }),
-            *allow.get(0).unwrap()
+            *allow.first().unwrap()
         );
         assert_eq!(
             Pattern::Mutation(MutationDef {
@@ -373,6 +803,12 @@ allow:
             Pattern::SomeString(String::from("*")),
             *allow.get(2).unwrap()
         );
+        assert_eq!(
+            Pattern::Subscription(SubscriptionDef {
+                subscription: String::from("heroCreated")
+            }),
+            *allow.get(3).unwrap()
+        );
     }
 
     #[test]
@@ -392,7 +828,7 @@ allow:
         let policies: Vec<Policy> = serde_yaml::from_str(s).unwrap();
         let first = policies.first().unwrap();
         let when = &first.when.as_ref().unwrap();
-        assert_eq!(When::claim_is_present("sub"), *when.get(0).unwrap());
+        assert_eq!(When::claim_is_present("sub"), *when.first().unwrap());
         assert_eq!(
             When::claim_equals("iss", "arboric.io"),
             *when.get(1).unwrap()
@@ -417,6 +853,9 @@ log_to:
   influx_db:
     uri: http://localhost:8086
     database: arboric
+log_claims:
+- sub
+- tenant_id
 policies:
 - when:
   - claim_is_present: sub
@@ -428,10 +867,14 @@ policies:
   - query: "*"
 "#;
         let listener: Listener = serde_yaml::from_str(s).unwrap();
+        assert_eq!(
+            Some(vec![String::from("sub"), String::from("tenant_id")]),
+            listener.log_claims
+        );
         let policies = listener.policies.unwrap();
         let first = policies.first().unwrap();
         let when = &first.when.as_ref().unwrap();
-        assert_eq!(When::claim_is_present("sub"), *when.get(0).unwrap());
+        assert_eq!(When::claim_is_present("sub"), *when.first().unwrap());
         assert_eq!(
             When::claim_equals("iss", "arboric.io"),
             *when.get(1).unwrap()
@@ -442,7 +885,7 @@ policies:
         );
         let allow = first.allow.as_ref().unwrap();
         assert_eq!(
-            allow.get(0).unwrap(),
+            allow.first().unwrap(),
             &Pattern::Query(QueryDef {
                 query: String::from("*")
             })
@@ -491,13 +934,64 @@ listeners:
         let policies = listener.policies.as_ref().unwrap();
         let policy = policies.first().unwrap();
         let when = &policy.when.as_ref().unwrap();
-        assert_eq!(When::claim_is_present("sub"), *when.get(0).unwrap());
+        assert_eq!(When::claim_is_present("sub"), *when.first().unwrap());
         assert_eq!(
             When::claim_equals("iss", "arboric.io"),
             *when.get(1).unwrap()
         );
     }
 
+    #[test]
+    fn test_yaml_config_log_to() {
+        let s = r#"---
+- influx_db:
+    uri: http://localhost:8086
+    database: arboric
+- file:
+    location: /var/log/arboric-usage.log
+- stdout
+- prometheus
+"#;
+        let log_to: LogTo = serde_yaml::from_str(s).unwrap();
+        assert_eq!(
+            vec![
+                &LogSink::InfluxDb(InfluxDbConfig {
+                    uri: String::from("http://localhost:8086"),
+                    database: String::from("arboric")
+                }),
+                &LogSink::File {
+                    location: String::from("/var/log/arboric-usage.log")
+                },
+                &LogSink::Stdout,
+                &LogSink::Prometheus
+            ],
+            log_to.sinks()
+        );
+
+        let log_to: LogTo = serde_yaml::from_str("stdout").unwrap();
+        assert_eq!(vec![&LogSink::Stdout], log_to.sinks());
+    }
+
+    #[test]
+    fn test_yaml_config_metrics() {
+        let s = r#"---
+log:
+  console:
+    level: info
+metrics:
+  bind: localhost
+  port: 9090
+"#;
+        let arboric: Arboric = serde_yaml::from_str(s).unwrap();
+        assert_eq!(
+            Some(Metrics {
+                bind: String::from("localhost"),
+                port: 9090
+            }),
+            arboric.metrics
+        );
+    }
+
     static JWT_FROM_FILE_YAML: &str = r#"
 arboric:
   log:
@@ -523,15 +1017,339 @@ listeners:
         let listener = listeners.first().unwrap();
         assert_eq!(
             listener.jwt_signing_key,
-            JwtSigningKey::FromFile {
+            Some(JwtSigningKey::FromFile {
                 from_file: FromFile {
                     name: String::from("etc/arboric/secret_key_bytes"),
-                    encoding: None
+                    encoding: None,
+                    algorithm: None,
                 }
-            }
+            })
         )
     }
 
+    #[test]
+    fn test_yaml_config_jwt_hmac_algorithm() {
+        let s = r#"---
+from_env:
+  key: SECRET_KEY_BASE
+  encoding: hex
+  algorithm: HS512
+"#;
+        let jwt_signing_key: JwtSigningKey = serde_yaml::from_str(s).unwrap();
+        assert_eq!(
+            crate::jwt::Algorithm::HS512,
+            hmac_algorithm(&jwt_signing_key)
+        );
+        let jwt_signing_key = JwtSigningKey::FromFile {
+            from_file: FromFile {
+                name: String::from("etc/arboric/secret_key_bytes"),
+                encoding: None,
+                algorithm: None,
+            },
+        };
+        assert_eq!(
+            crate::jwt::Algorithm::HS256,
+            hmac_algorithm(&jwt_signing_key)
+        );
+    }
+
+    #[test]
+    fn test_yaml_config_jwt_public_key() {
+        let s = r#"---
+public_key:
+  name: /etc/arboric/jwt.pem
+  algorithm: RS256
+"#;
+        let jwt_signing_key: JwtSigningKey = serde_yaml::from_str(s).unwrap();
+        assert_eq!(
+            JwtSigningKey::PublicKey {
+                public_key: PublicKey {
+                    name: String::from("/etc/arboric/jwt.pem"),
+                    algorithm: String::from("RS256"),
+                }
+            },
+            jwt_signing_key
+        );
+    }
+
+    #[test]
+    fn test_yaml_config_jwks() {
+        let s = r#"---
+jwks:
+  uri: https://login.example.com/.well-known/jwks.json
+  refresh_interval: 600
+  timeout: 5
+"#;
+        let jwt_signing_key: JwtSigningKey = serde_yaml::from_str(s).unwrap();
+        assert_eq!(
+            JwtSigningKey::Jwks {
+                jwks: Jwks {
+                    uri: Some(String::from(
+                        "https://login.example.com/.well-known/jwks.json"
+                    )),
+                    file: None,
+                    refresh_interval: Some(600),
+                    timeout: Some(5),
+                }
+            },
+            jwt_signing_key
+        );
+    }
+
+    #[test]
+    fn test_yaml_config_jwt_signing_keys() {
+        let s = r#"---
+bind: localhost
+port: 4000
+proxy: http://localhost:3001/graphql
+jwt_signing_keys:
+- kid: "2019-10"
+  from_file:
+    name: /etc/arboric/secret_key_2019_10
+- kid: "2019-07"
+  from_file:
+    name: /etc/arboric/secret_key_2019_07
+- jwks:
+    file: /etc/arboric/jwks.json
+"#;
+        let listener: Listener = serde_yaml::from_str(s).unwrap();
+        assert!(listener.jwt_signing_key.is_none());
+        let jwt_signing_keys = listener.jwt_signing_keys.unwrap();
+        assert_eq!(3, jwt_signing_keys.len());
+        assert_eq!(
+            SigningKey {
+                kid: Some(String::from("2019-10")),
+                key: JwtSigningKey::FromFile {
+                    from_file: FromFile {
+                        name: String::from("/etc/arboric/secret_key_2019_10"),
+                        encoding: None,
+                        algorithm: None,
+                    }
+                }
+            },
+            jwt_signing_keys[0]
+        );
+        assert_eq!(None, jwt_signing_keys[2].kid);
+    }
+
+    #[test]
+    fn test_yaml_config_anonymous() {
+        let s = r#"---
+bind: localhost
+port: 4000
+proxy: http://localhost:3001/graphql
+jwt_signing_key:
+  from_env:
+    key: SECRET_KEY_BASE
+    encoding: hex
+anonymous: true
+require_allow: true
+error_verbosity: verbose
+policies:
+- allow:
+  - query: publicPosts
+"#;
+        let listener: Listener = serde_yaml::from_str(s).unwrap();
+        assert_eq!(Some(true), listener.anonymous);
+        assert_eq!(Some(true), listener.require_allow);
+        assert_eq!(Some(String::from("verbose")), listener.error_verbosity);
+    }
+
+    #[test]
+    fn test_yaml_config_batch() {
+        let s = r#"---
+bind: localhost
+port: 4000
+proxy: http://localhost:3001/graphql
+batch:
+  max_size: 5
+  on_denied: deny_entries
+"#;
+        let listener: Listener = serde_yaml::from_str(s).unwrap();
+        assert_eq!(
+            Some(Batch {
+                max_size: Some(5),
+                on_denied: Some(String::from("deny_entries"))
+            }),
+            listener.batch
+        );
+    }
+
+    #[test]
+    fn test_yaml_config_schema() {
+        let s = r#"---
+bind: localhost
+port: 4000
+proxy: http://localhost:3001/graphql
+schema:
+  from_file: /etc/arboric/schema.graphql
+policies:
+- allow:
+  - "*"
+  deny:
+  - type: User{email}
+  - "type:Secret"
+"#;
+        let listener: Listener = serde_yaml::from_str(s).unwrap();
+        assert_eq!(
+            Some(Schema::FromFile {
+                from_file: String::from("/etc/arboric/schema.graphql")
+            }),
+            listener.schema
+        );
+        let policies = listener.policies.unwrap();
+        let deny = policies[0].deny.as_ref().unwrap();
+        assert_eq!(
+            Pattern::Type(TypeDef {
+                type_pattern: String::from("User{email}")
+            }),
+            deny[0]
+        );
+        assert_eq!(
+            graphql::Pattern::of_type("Secret"),
+            pattern_def_to_graphql_pattern(&deny[1]).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_yaml_config_invalid_pattern() {
+        let s = r#"---
+bind: localhost
+port: 4000
+proxy: http://localhost:3001/graphql
+policies:
+- allow:
+  - "*"
+  deny:
+  - query: "secret("
+"#;
+        let listener: Listener = serde_yaml::from_str(s).unwrap();
+        match listener_policies(&listener) {
+            Err(ArboricError::GeneralError { message }) => {
+                assert!(message.starts_with(r#"Invalid field pattern "secret(""#))
+            }
+            Err(err) => panic!("Expected ArboricError::GeneralError, got {:?}!", err),
+            Ok(_) => panic!("Expected listener_policies() to fail!"),
+        }
+
+        for pattern in &["mutation:foo(", "subscription:["] {
+            let def = Pattern::SomeString(String::from(*pattern));
+            assert!(pattern_def_to_graphql_pattern(&def).is_err());
+        }
+        // Dotted paths and type pattern fields are matched literally
+        for pattern in &["foo.bar(", "type:Hero{secret(}"] {
+            let def = Pattern::SomeString(String::from(*pattern));
+            assert!(pattern_def_to_graphql_pattern(&def).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_yaml_config_jwt_validation() {
+        let s = r#"---
+bind: localhost
+port: 4000
+proxy: http://localhost:3001/graphql
+jwt_signing_key:
+  from_env:
+    key: SECRET_KEY_BASE
+    encoding: hex
+jwt_validation:
+  issuers:
+  - https://login.example.com/
+  leeway: 30
+"#;
+        let listener: Listener = serde_yaml::from_str(s).unwrap();
+        assert_eq!(
+            Some(JwtValidation {
+                issuers: Some(vec![String::from("https://login.example.com/")]),
+                audiences: None,
+                leeway: Some(30),
+                max_age: None,
+            }),
+            listener.jwt_validation
+        );
+    }
+
+    #[test]
+    fn test_yaml_config_listener_tls() {
+        let s = r#"---
+bind: 0.0.0.0
+port: 443
+tls:
+  certificate_chain: /etc/arboric/tls/fullchain.pem
+  private_key: /etc/arboric/tls/privkey.pem
+  http2: true
+proxy: http://localhost:3001/graphql
+jwt_signing_key:
+  from_env:
+    key: SECRET_KEY_BASE
+    encoding: hex
+"#;
+        let listener: Listener = serde_yaml::from_str(s).unwrap();
+        assert_eq!(
+            Some(Tls {
+                certificate_chain: String::from("/etc/arboric/tls/fullchain.pem"),
+                private_key: String::from("/etc/arboric/tls/privkey.pem"),
+                http2: Some(true),
+                client_auth: None,
+            }),
+            listener.tls
+        );
+    }
+
+    #[test]
+    fn test_yaml_config_listener_tls_client_auth() {
+        let s = r#"---
+certificate_chain: /etc/arboric/tls/fullchain.pem
+private_key: /etc/arboric/tls/privkey.pem
+client_auth:
+  ca_bundle: /etc/arboric/tls/clients-ca.pem
+  claims:
+    subject: client_dn
+"#;
+        let tls: Tls = serde_yaml::from_str(s).unwrap();
+        assert_eq!(
+            Some(ClientAuth {
+                ca_bundle: String::from("/etc/arboric/tls/clients-ca.pem"),
+                required: None,
+                claims: Some(ClientCertificateClaims {
+                    subject: Some(String::from("client_dn")),
+                    subject_alt_names: None,
+                    issuer: None,
+                }),
+            }),
+            tls.client_auth
+        );
+    }
+
+    #[test]
+    fn test_yaml_config_listener_upstream_tls() {
+        let s = r#"---
+bind: localhost
+port: 4000
+proxy: https://api.internal:8443/graphql
+upstream_tls:
+  ca_bundle: /etc/arboric/tls/upstream-ca.pem
+  client_certificate: /etc/arboric/tls/client.pem
+  client_key: /etc/arboric/tls/client.key
+jwt_signing_key:
+  from_env:
+    key: SECRET_KEY_BASE
+    encoding: hex
+"#;
+        let listener: Listener = serde_yaml::from_str(s).unwrap();
+        assert!(listener.tls.is_none());
+        assert_eq!(
+            Some(UpstreamTls {
+                ca_bundle: Some(String::from("/etc/arboric/tls/upstream-ca.pem")),
+                client_certificate: Some(String::from("/etc/arboric/tls/client.pem")),
+                client_key: Some(String::from("/etc/arboric/tls/client.key")),
+                server_name: None,
+            }),
+            listener.upstream_tls
+        );
+    }
+
     #[test]
     fn test_yaml_config_from_file() {
         let path = std::path::PathBuf::from("etc/arboric/config.yml");
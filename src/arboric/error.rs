@@ -1,5 +1,8 @@
 //! The ArboricError is an Error type used to 'wrap' most other
 //! runtime errors
+// failure's derive defines its impls inside a const
+#![allow(non_local_definitions)]
+
 use failure::Fail;
 
 /// Arboric error type to 'wrap' other, underlying error causes
@@ -8,6 +11,10 @@ pub enum ArboricError {
     #[fail(display = "{}", message)]
     GeneralError { message: String },
 
+    /// A GraphQL document that parses, but can't be executed as it is
+    #[fail(display = "{}", message)]
+    GraphqlValidationError { message: String },
+
     #[fail(display = "Unable to find or decode ENV['{}']", message)]
     EnvVarError {
         message: String,
@@ -50,12 +57,33 @@ pub enum ArboricError {
         cause: serde_yaml::Error,
     },
 
+    #[fail(display = "{}", message)]
+    HyperError {
+        message: String,
+        #[cause]
+        cause: hyper::Error,
+    },
+
+    #[fail(display = "{}", message)]
+    OpenSslError {
+        message: String,
+        #[cause]
+        cause: openssl::error::ErrorStack,
+    },
+
     #[fail(display = "{}", message)]
     GraphqlParserError {
         message: String,
         #[cause]
         cause: graphql_parser::query::ParseError,
     },
+
+    #[fail(display = "{}", message)]
+    GraphqlSchemaParserError {
+        message: String,
+        #[cause]
+        cause: graphql_parser::schema::ParseError,
+    },
 }
 
 impl ArboricError {
@@ -64,6 +92,12 @@ impl ArboricError {
             message: message.into(),
         }
     }
+
+    pub fn validation<S: Into<String>>(message: S) -> ArboricError {
+        ArboricError::GraphqlValidationError {
+            message: message.into(),
+        }
+    }
 }
 
 // impl std::error::Error for ArboricError {
@@ -147,6 +181,24 @@ impl From<serde_yaml::Error> for ArboricError {
     }
 }
 
+impl From<hyper::Error> for ArboricError {
+    fn from(hyper_error: hyper::Error) -> Self {
+        ArboricError::HyperError {
+            message: format!("{:?}", hyper_error),
+            cause: hyper_error,
+        }
+    }
+}
+
+impl From<openssl::error::ErrorStack> for ArboricError {
+    fn from(error_stack: openssl::error::ErrorStack) -> Self {
+        ArboricError::OpenSslError {
+            message: format!("{:?}", error_stack),
+            cause: error_stack,
+        }
+    }
+}
+
 impl From<graphql_parser::query::ParseError> for ArboricError {
     fn from(parser_error: graphql_parser::query::ParseError) -> Self {
         ArboricError::GraphqlParserError {
@@ -155,3 +207,12 @@ impl From<graphql_parser::query::ParseError> for ArboricError {
         }
     }
 }
+
+impl From<graphql_parser::schema::ParseError> for ArboricError {
+    fn from(parser_error: graphql_parser::schema::ParseError) -> Self {
+        ArboricError::GraphqlSchemaParserError {
+            message: format!("{:?}", parser_error),
+            cause: parser_error,
+        }
+    }
+}
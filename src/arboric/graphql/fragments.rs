@@ -0,0 +1,253 @@
+//! Expands fragment spreads into inline fragments, so that patterns can be
+//! matched against (and fields counted in) everything a query selects
+
+use crate::ArboricError;
+use graphql_parser::query::{
+    Definition, Document, Field, FragmentDefinition, InlineFragment, OperationDefinition,
+    Selection, SelectionSet,
+};
+use log::trace;
+use std::collections::HashMap;
+
+/// The most selections a document can expand to. Each fragment can spread the next
+/// one several times, so a small document could otherwise expand exponentially.
+const MAX_EXPANDED_SELECTIONS: usize = 10_000;
+
+/// Returns a copy of the given document with every fragment spread (`...F`) replaced
+/// by an inline fragment (`... on T { ... }`) with the fragment's selections, and
+/// without the fragment definitions.
+///
+/// Returns an error if two fragments have the same name, if a spread refers to an
+/// unknown fragment, if fragments spread each other in a cycle, or if the expanded
+/// operations would have more than 10,000 selections.
+pub fn expand_fragments(document: &Document) -> crate::Result<Document> {
+    let mut fragments: HashMap<&str, &FragmentDefinition> = HashMap::new();
+    for definition in document.definitions.iter() {
+        if let Definition::Fragment(fragment) = definition {
+            if fragments.insert(fragment.name.as_str(), fragment).is_some() {
+                return Err(ArboricError::validation(format!(
+                    r#"There can be only one fragment named "{}"."#,
+                    fragment.name
+                )));
+            }
+        }
+    }
+    trace!("fragments => {:?}", fragments.keys());
+
+    let mut selections = 0;
+    let mut definitions = Vec::new();
+    for definition in document.definitions.iter() {
+        if let Definition::Operation(operation_definition) = definition {
+            let mut expanded = operation_definition.clone();
+            let selection_set = match expanded {
+                OperationDefinition::SelectionSet(ref mut selection_set) => selection_set,
+                OperationDefinition::Query(ref mut query) => &mut query.selection_set,
+                OperationDefinition::Mutation(ref mut mutation) => &mut mutation.selection_set,
+                OperationDefinition::Subscription(ref mut subscription) => {
+                    &mut subscription.selection_set
+                }
+            };
+            expand_selection_set(selection_set, &fragments, &mut Vec::new(), &mut selections)?;
+            definitions.push(Definition::Operation(expanded));
+        }
+    }
+    Ok(Document { definitions })
+}
+
+/// Whether the document has any fragment definitions to expand
+pub fn has_fragments(document: &Document) -> bool {
+    document
+        .definitions
+        .iter()
+        .any(|definition| matches!(definition, Definition::Fragment(_)))
+}
+
+/// Expands the fragment spreads in the selection set, in place. `path` is the
+/// chain of fragments being expanded, for cycle detection, and `selections` the
+/// number of selections expanded so far.
+fn expand_selection_set<'a>(
+    selection_set: &mut SelectionSet,
+    fragments: &HashMap<&str, &'a FragmentDefinition>,
+    path: &mut Vec<&'a str>,
+    selections: &mut usize,
+) -> crate::Result<()> {
+    *selections += selection_set.items.len();
+    if *selections > MAX_EXPANDED_SELECTIONS {
+        return Err(ArboricError::validation(format!(
+            "Query has more than {} selections once its fragments are expanded.",
+            MAX_EXPANDED_SELECTIONS
+        )));
+    }
+    for selection in selection_set.items.iter_mut() {
+        let expanded = match selection {
+            Selection::Field(ref mut field) => {
+                expand_selection_set(&mut field.selection_set, fragments, path, selections)?;
+                None
+            }
+            Selection::InlineFragment(ref mut inline_fragment) => {
+                expand_selection_set(
+                    &mut inline_fragment.selection_set,
+                    fragments,
+                    path,
+                    selections,
+                )?;
+                None
+            }
+            Selection::FragmentSpread(ref spread) => {
+                let fragment = match fragments.get(spread.fragment_name.as_str()) {
+                    Some(fragment) => *fragment,
+                    None => {
+                        return Err(ArboricError::validation(format!(
+                            r#"Unknown fragment "{}"."#,
+                            spread.fragment_name
+                        )))
+                    }
+                };
+                if path.contains(&fragment.name.as_str()) {
+                    return Err(ArboricError::validation(format!(
+                        "Fragment cycle: {} -> {}.",
+                        path.join(" -> "),
+                        fragment.name
+                    )));
+                }
+                path.push(fragment.name.as_str());
+                let mut fragment_selection_set = fragment.selection_set.clone();
+                expand_selection_set(&mut fragment_selection_set, fragments, path, selections)?;
+                path.pop();
+                Some(Selection::InlineFragment(InlineFragment {
+                    position: spread.position,
+                    type_condition: Some(fragment.type_condition.clone()),
+                    directives: spread.directives.clone(),
+                    selection_set: fragment_selection_set,
+                }))
+            }
+        };
+        if let Some(inline_fragment) = expanded {
+            *selection = inline_fragment;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the fields selected in the given selection set, including those selected
+/// through (inline) fragments, but not their sub-fields. Fragment spreads should
+/// have been expanded first.
+pub fn fields(selection_set: &SelectionSet) -> Vec<&Field> {
+    let mut fields = Vec::new();
+    for selection in selection_set.items.iter() {
+        match selection {
+            Selection::Field(field) => fields.push(field),
+            Selection::InlineFragment(inline_fragment) => {
+                fields.extend(self::fields(&inline_fragment.selection_set))
+            }
+            Selection::FragmentSpread(spread) => {
+                trace!("Unexpanded fragment spread {:?}", spread.fragment_name)
+            }
+        }
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    // Import names from outer (for mod tests) scope.
+    use super::*;
+
+    fn top_level_field_names(document: &Document) -> Vec<String> {
+        match document.definitions.first().unwrap() {
+            Definition::Operation(OperationDefinition::SelectionSet(selection_set)) => {
+                fields(selection_set)
+                    .iter()
+                    .map(|field| field.name.clone())
+                    .collect()
+            }
+            x => panic!("Don't know what to do with {:?}!", x),
+        }
+    }
+
+    #[test]
+    fn test_expand_fragments() {
+        crate::initialize_test_logging();
+        let document = graphql_parser::parse_query(
+            "{ hero { name } ...F } fragment F on Query { ...G secret } fragment G on Query { other }",
+        )
+        .unwrap();
+        assert!(has_fragments(&document));
+        let expanded = expand_fragments(&document).unwrap();
+        assert!(!has_fragments(&expanded));
+        assert_eq!(1, expanded.definitions.len());
+        assert_eq!(
+            vec!["hero", "other", "secret"],
+            top_level_field_names(&expanded)
+        );
+    }
+
+    #[test]
+    fn test_expand_nested_fragments() {
+        crate::initialize_test_logging();
+        let document = graphql_parser::parse_query(
+            "{ hero { ...HeroFields } } fragment HeroFields on Hero { secretIdentity }",
+        )
+        .unwrap();
+        let expanded = expand_fragments(&document).unwrap();
+        let expected =
+            graphql_parser::parse_query("{ hero { ... on Hero { secretIdentity } } }").unwrap();
+        // Positions differ, so compare the printed documents
+        assert_eq!(expected.to_string(), expanded.to_string());
+    }
+
+    #[test]
+    fn test_expand_fragments_errors() {
+        crate::initialize_test_logging();
+        let unknown = graphql_parser::parse_query("{ ...F }").unwrap();
+        match expand_fragments(&unknown) {
+            Err(err) => assert_eq!(r#"Unknown fragment "F"."#, err.to_string()),
+            Ok(document) => panic!("Expected an unknown fragment error, got {:?}!", document),
+        }
+
+        let cycle = graphql_parser::parse_query(
+            "{ ...A } fragment A on Query { ...B } fragment B on Query { ...A }",
+        )
+        .unwrap();
+        match expand_fragments(&cycle) {
+            Err(err) => assert_eq!("Fragment cycle: A -> B -> A.", err.to_string()),
+            Ok(document) => panic!("Expected a fragment cycle error, got {:?}!", document),
+        }
+
+        let duplicate = graphql_parser::parse_query(
+            "{ ...A } fragment A on Query { publicPosts } fragment A on Query { secrets }",
+        )
+        .unwrap();
+        match expand_fragments(&duplicate) {
+            Err(err) => assert_eq!(
+                r#"There can be only one fragment named "A"."#,
+                err.to_string()
+            ),
+            Ok(document) => panic!("Expected a duplicate fragment error, got {:?}!", document),
+        }
+    }
+
+    #[test]
+    fn test_expand_fragments_is_limited() {
+        crate::initialize_test_logging();
+        // Each fragment spreads the next twice, so F1 would expand to 2^30 selections
+        let mut query = String::from("{ ...F1 }");
+        for i in 1..30 {
+            query.push_str(&format!(
+                " fragment F{} on Query {{ ...F{} ...F{} }}",
+                i,
+                i + 1,
+                i + 1
+            ));
+        }
+        query.push_str(" fragment F30 on Query { a }");
+        let document = graphql_parser::parse_query(&query).unwrap();
+        match expand_fragments(&document) {
+            Err(ArboricError::GraphqlValidationError { message }) => assert_eq!(
+                "Query has more than 10000 selections once its fragments are expanded.",
+                message
+            ),
+            x => panic!("Expected a validation error, got {:?}!", x),
+        }
+    }
+}
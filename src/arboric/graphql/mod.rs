@@ -1,5 +1,18 @@
 //! Arboric GraphQL utility modules and functions
 
+mod fragments;
+mod operations;
 mod pattern;
+mod schema;
+mod validation;
 
-pub use pattern::Pattern;
+pub use fragments::{expand_fragments, fields, has_fragments};
+pub use operations::{
+    document_operation, document_operation_type, operation_name, operation_type, select_operation,
+};
+pub use pattern::{FieldPath, FieldPattern, Pattern, TypePattern};
+pub use schema::Schema;
+pub use validation::validate;
+
+#[cfg(test)]
+pub use schema::test_support;
@@ -0,0 +1,193 @@
+//! Selects the operation to execute from a GraphQL document, as in the
+//! [GetOperation](https://spec.graphql.org/June2018/#GetOperation()) algorithm
+
+use crate::ArboricError;
+use graphql_parser::query::{Definition, Document, OperationDefinition};
+use log::trace;
+use std::collections::HashSet;
+
+/// Returns a copy of the document with only the operation named by `operation_name`,
+/// or its only operation if no name is given, and the fragment definitions.
+///
+/// Returns an error if two operations have the same name, if there's no such
+/// operation, or if no name is given and the document has more than one operation.
+pub fn select_operation(
+    document: &Document,
+    operation_name: Option<&str>,
+) -> crate::Result<Document> {
+    trace!("select_operation({:?})", operation_name);
+    let operations: Vec<&Definition> = document
+        .definitions
+        .iter()
+        .filter(|definition| matches!(definition, Definition::Operation(_)))
+        .collect();
+    // Servers differ in which of several operations with the same name they execute,
+    // so the one that's authorized might not be the one that runs
+    let mut names = HashSet::new();
+    for definition in operations.iter() {
+        if let Definition::Operation(operation_definition) = definition {
+            if let Some(name) = self::operation_name(operation_definition) {
+                if !names.insert(name) {
+                    return Err(ArboricError::validation(format!(
+                        r#"There can be only one operation named "{}"."#,
+                        name
+                    )));
+                }
+            }
+        }
+    }
+    let selected = match operation_name {
+        Some(name) => operations
+            .into_iter()
+            .find(|definition| match definition {
+                Definition::Operation(operation_definition) => {
+                    self::operation_name(operation_definition) == Some(name)
+                }
+                _ => false,
+            })
+            .ok_or_else(|| {
+                ArboricError::validation(format!(r#"Unknown operation named "{}"."#, name))
+            })?,
+        None => match operations.len() {
+            1 => operations[0],
+            0 => return Err(ArboricError::validation("Document contains no operations.")),
+            _ => {
+                return Err(ArboricError::validation(
+                    "Must provide operation name if query contains multiple operations.",
+                ))
+            }
+        },
+    };
+    let definitions = document
+        .definitions
+        .iter()
+        .filter(|definition| match definition {
+            Definition::Operation(_) => std::ptr::eq(*definition, selected),
+            Definition::Fragment(_) => true,
+        })
+        .cloned()
+        .collect();
+    Ok(Document { definitions })
+}
+
+/// The name of the operation, if it has one
+pub fn operation_name(operation_definition: &OperationDefinition) -> Option<&str> {
+    match operation_definition {
+        OperationDefinition::SelectionSet(_) => None,
+        OperationDefinition::Query(query) => query.name.as_deref(),
+        OperationDefinition::Mutation(mutation) => mutation.name.as_deref(),
+        OperationDefinition::Subscription(subscription) => subscription.name.as_deref(),
+    }
+}
+
+/// The type of the operation: `"query"`, `"mutation"` or `"subscription"`
+pub fn operation_type(operation_definition: &OperationDefinition) -> &'static str {
+    match operation_definition {
+        OperationDefinition::SelectionSet(_) | OperationDefinition::Query(_) => "query",
+        OperationDefinition::Mutation(_) => "mutation",
+        OperationDefinition::Subscription(_) => "subscription",
+    }
+}
+
+/// The document's operation, or its first operation if it has several. `None` if it
+/// has no operations.
+pub fn document_operation(document: &Document) -> Option<&OperationDefinition> {
+    document
+        .definitions
+        .iter()
+        .filter_map(|definition| match definition {
+            Definition::Operation(operation_definition) => Some(operation_definition),
+            _ => None,
+        })
+        .next()
+}
+
+/// The type of the document's operation (see [operation_type](fn.operation_type.html)),
+/// or of its first operation if it has several. `None` if it has no operations.
+pub fn document_operation_type(document: &Document) -> Option<&'static str> {
+    document_operation(document).map(operation_type)
+}
+
+#[cfg(test)]
+mod tests {
+    // Import names from outer (for mod tests) scope.
+    use super::*;
+
+    const DOCUMENT: &str = "query A { a ...F } query B { b } fragment F on Query { f }";
+
+    #[test]
+    fn test_select_operation() {
+        crate::initialize_test_logging();
+        let document = graphql_parser::parse_query(DOCUMENT).unwrap();
+        let selected = select_operation(&document, Some("B")).unwrap();
+        let expected =
+            graphql_parser::parse_query("query B { b } fragment F on Query { f }").unwrap();
+        // Positions differ, so compare the printed documents
+        assert_eq!(expected.to_string(), selected.to_string());
+
+        let single = graphql_parser::parse_query("{ a }").unwrap();
+        assert_eq!(single, select_operation(&single, None).unwrap());
+    }
+
+    #[test]
+    fn test_select_operation_errors() {
+        crate::initialize_test_logging();
+        let document = graphql_parser::parse_query(DOCUMENT).unwrap();
+        assert_eq!(
+            "Must provide operation name if query contains multiple operations.",
+            select_operation(&document, None).unwrap_err().to_string()
+        );
+        assert_eq!(
+            r#"Unknown operation named "C"."#,
+            select_operation(&document, Some("C"))
+                .unwrap_err()
+                .to_string()
+        );
+        let fragments_only = graphql_parser::parse_query("fragment F on Query { f }").unwrap();
+        assert!(select_operation(&fragments_only, None).is_err());
+    }
+
+    #[test]
+    fn test_select_operation_rejects_duplicate_names() {
+        crate::initialize_test_logging();
+        let document =
+            graphql_parser::parse_query("query A { publicPosts } query A { secrets }").unwrap();
+        for operation_name in &[Some("A"), None] {
+            assert_eq!(
+                r#"There can be only one operation named "A"."#,
+                select_operation(&document, *operation_name)
+                    .unwrap_err()
+                    .to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn test_operation_type() {
+        let document =
+            graphql_parser::parse_query("{ a } query { a } mutation { b } subscription { c }")
+                .unwrap();
+        let types: Vec<&str> = document
+            .definitions
+            .iter()
+            .filter_map(|definition| match definition {
+                Definition::Operation(operation_definition) => {
+                    Some(operation_type(operation_definition))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec!["query", "query", "mutation", "subscription"], types);
+
+        let mutation =
+            graphql_parser::parse_query("fragment F on M { b } mutation { ...F }").unwrap();
+        assert_eq!(Some("mutation"), document_operation_type(&mutation));
+        let fragments_only = graphql_parser::parse_query("fragment F on Query { f }").unwrap();
+        assert_eq!(None, document_operation_type(&fragments_only));
+        let named = graphql_parser::parse_query("fragment F on Q { a } query A { ...F }").unwrap();
+        assert_eq!(
+            Some("A"),
+            document_operation(&named).and_then(operation_name)
+        );
+    }
+}
@@ -1,9 +1,11 @@
 //! Represents a pattern that can be used to match incoming
-//! GraphQL requests (queries or mutations) by field, type, etc.
+//! GraphQL requests (queries, mutations or subscriptions) by field, type, etc.
 //! Used for ABAC/ACLs, and selective logging.
 
-use graphql_parser::query::{Field, OperationDefinition, Selection};
-use log::trace;
+use super::{fields, Schema};
+use crate::ArboricError;
+use graphql_parser::query::{Field, OperationDefinition};
+use log::{trace, warn};
 use regex::Regex;
 use std::borrow::Borrow;
 use std::fmt;
@@ -12,11 +14,16 @@ use std::fmt;
 ///   * `Any` - or `*` will match anything
 ///   * `Query` - or `query:...` will match a query
 ///   * `Mutation` - or `mutation:...` will match a mutation
+///   * `Subscription` - or `subscription:...` will match a subscription
+///   * `Type` - or `type:...` will match an operation that selects a field of, or on,
+///     a given type. This needs a [Schema](arboric::graphql::Schema)
 #[derive(Debug, Clone, PartialEq)]
 pub enum Pattern {
     Any,
     Query(FieldPattern),
     Mutation(FieldPattern),
+    Subscription(FieldPattern),
+    Type(TypePattern),
 }
 
 impl Pattern {
@@ -32,36 +39,80 @@ impl Pattern {
     /// assert_eq!(Pattern::parse("foo"), Pattern::query("foo"));
     /// assert_eq!(Pattern::parse("query:foo"), Pattern::query("foo"));
     /// assert_eq!(Pattern::parse("mutation:bar"), Pattern::mutation("bar"));
+    /// assert_eq!(Pattern::parse("subscription:baz"), Pattern::subscription("baz"));
+    /// assert_eq!(Pattern::parse("**.secretIdentity"), Pattern::query("**.secretIdentity"));
+    /// assert_eq!(Pattern::parse("type:Hero"), Pattern::of_type("Hero"));
+    /// assert_eq!(Pattern::parse("type:Hero{secretIdentity}").to_string(), "type:Hero{secretIdentity}");
     /// ```
+    ///
+    /// Panics if the pattern is invalid, see [try_parse](#method.try_parse)
     pub fn parse<S>(s: S) -> Pattern
+    where
+        S: Into<String> + PartialEq,
+    {
+        Pattern::try_parse(s).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Parses the given pattern string like [parse](#method.parse), but returns an
+    /// error instead of panicking if the pattern is invalid
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use arboric::graphql::Pattern;
+    ///
+    /// assert_eq!(Pattern::try_parse("query:foo").unwrap(), Pattern::query("foo"));
+    /// assert!(Pattern::try_parse("query:foo(").is_err());
+    /// ```
+    pub fn try_parse<S>(s: S) -> crate::Result<Pattern>
     where
         S: Into<String> + PartialEq,
     {
         let pattern: String = s.into();
         if pattern == "*" {
-            Pattern::Any
+            Ok(Pattern::Any)
+        } else if let Some(s) = pattern.strip_prefix("mutation:") {
+            FieldPattern::parse(s).map(Pattern::Mutation)
+        } else if let Some(s) = pattern.strip_prefix("subscription:") {
+            FieldPattern::parse(s).map(Pattern::Subscription)
+        } else if let Some(s) = pattern.strip_prefix("type:") {
+            TypePattern::parse(s).map(Pattern::Type)
+        } else if let Some(s) = pattern.strip_prefix("query:") {
+            FieldPattern::parse(s).map(Pattern::Query)
         } else {
-            if pattern.starts_with("mutation:") {
-                Pattern::mutation(&pattern.as_str()[9..])
-            } else if pattern.starts_with("query:") {
-                Pattern::query(&pattern.as_str()[6..])
-            } else {
-                Pattern::query(&pattern.as_str())
-            }
+            FieldPattern::parse(&pattern).map(Pattern::Query)
         }
     }
 
     /// Constructs a Pattern::Query with the given FieldPattern string
     pub fn query(s: &str) -> Pattern {
-        Pattern::Query(FieldPattern(s.into()))
+        Pattern::Query(FieldPattern::new(s))
     }
 
     /// Constructs a Pattern::Mutation with then given FieldPattern string
     pub fn mutation(s: &str) -> Pattern {
-        Pattern::Mutation(FieldPattern(s.into()))
+        Pattern::Mutation(FieldPattern::new(s))
+    }
+
+    /// Constructs a Pattern::Subscription with the given FieldPattern string
+    pub fn subscription(s: &str) -> Pattern {
+        Pattern::Subscription(FieldPattern::new(s))
+    }
+
+    /// Constructs a Pattern::Type from the given TypePattern string, e.g. `Hero` or
+    /// `Hero{secretIdentity, name}`
+    pub fn of_type(s: &str) -> Pattern {
+        Pattern::Type(TypePattern::parse(s).unwrap_or_else(|err| panic!("{}", err)))
     }
 
-    /// Compares this Pattern against the GraphQL AST Field if it matches
+    /// Whether this Pattern can only be matched with a [Schema](arboric::graphql::Schema)
+    pub fn requires_schema(&self) -> bool {
+        matches!(self, Pattern::Type(_))
+    }
+
+    /// Compares this Pattern against the GraphQL AST Field if it matches, anywhere in
+    /// the operation's selection set tree. Fields in inline fragments are matched too;
+    /// fragment spreads should be expanded first.
     ///
     /// # Examples
     ///
@@ -80,45 +131,121 @@ impl Pattern {
     /// }
     ///
     pub fn matches(&self, operation_definition: &OperationDefinition) -> bool {
+        self.matches_with_schema(operation_definition, None)
+    }
+
+    /// Compares this Pattern against the operation, resolving the types of the
+    /// selected fields using the given schema. `Type` patterns never match without one.
+    pub fn matches_with_schema(
+        &self,
+        operation_definition: &OperationDefinition,
+        schema: Option<&Schema>,
+    ) -> bool {
         trace!("matches({:?}, {:?})", &self, &operation_definition);
         match self {
             Pattern::Any => true,
             Pattern::Query(ref field_pattern) => match operation_definition {
-                OperationDefinition::Query(query) => {
-                    query
-                        .selection_set
-                        .items
-                        .iter()
-                        .any(|selection| match selection {
-                            Selection::Field(field) => field_pattern.matches(field),
-                            _ => false,
-                        })
-                }
-                OperationDefinition::SelectionSet(selection_set) => {
-                    selection_set.items.iter().any(|selection| match selection {
-                        Selection::Field(field) => field_pattern.matches(field),
-                        _ => false,
-                    })
+                OperationDefinition::Query(query) => fields(&query.selection_set)
+                    .into_iter()
+                    .any(|field| field_pattern.matches(field)),
+                OperationDefinition::SelectionSet(selection_set) => fields(selection_set)
+                    .into_iter()
+                    .any(|field| field_pattern.matches(field)),
+                _ => false,
+            },
+            Pattern::Mutation(ref field_pattern) => match operation_definition {
+                OperationDefinition::Mutation(mutation) => fields(&mutation.selection_set)
+                    .into_iter()
+                    .any(|field| field_pattern.matches(field)),
+                _ => false,
+            },
+            Pattern::Subscription(ref field_pattern) => match operation_definition {
+                OperationDefinition::Subscription(subscription) => {
+                    fields(&subscription.selection_set)
+                        .into_iter()
+                        .any(|field| field_pattern.matches(field))
                 }
                 _ => false,
             },
-            Pattern::Mutation(ref field_pattern) => {
-                match operation_definition {
-                    OperationDefinition::Mutation(mutation) => mutation
-                        .selection_set
-                        .items
-                        .iter()
-                        .any(|selection| match selection {
-                            Selection::Field(field) => field_pattern.matches(field),
-                            _ => false,
-                        }),
-                    _ => false,
+            Pattern::Type(ref type_pattern) => match schema {
+                Some(schema) => type_pattern.matches(operation_definition, schema),
+                None => {
+                    warn!("No schema to match {} against", &self);
+                    false
                 }
+            },
+        }
+    }
+
+    /// Compares this Pattern against a single path of fields the operation selects,
+    /// from a top-level field down. Field patterns match the path as a whole, or up
+    /// to one of its fields, and type patterns match any field on it.
+    pub fn matches_field_path(
+        &self,
+        operation_definition: &OperationDefinition,
+        path: &FieldPath,
+        schema: Option<&Schema>,
+    ) -> bool {
+        trace!("matches_field_path({:?}, {:?})", &self, path);
+        match (self, operation_definition) {
+            (Pattern::Any, _) => true,
+            (Pattern::Query(ref field_pattern), OperationDefinition::Query(_))
+            | (Pattern::Query(ref field_pattern), OperationDefinition::SelectionSet(_))
+            | (Pattern::Mutation(ref field_pattern), OperationDefinition::Mutation(_))
+            | (Pattern::Subscription(ref field_pattern), OperationDefinition::Subscription(_)) => {
+                field_pattern.matches_path(path.fields())
             }
+            (Pattern::Type(ref type_pattern), _) => match schema {
+                Some(schema) => type_pattern.matches_path(path, schema),
+                None => {
+                    warn!("No schema to match {} against", &self);
+                    false
+                }
+            },
+            _ => false,
         }
     }
 }
 
+/// A path of fields selected one under the other, from a top-level field of an
+/// operation down, with the type each is selected on (if known), so that patterns
+/// can be matched against each path an operation selects without copying it
+#[derive(Debug, Default)]
+pub struct FieldPath<'a> {
+    fields: Vec<&'a Field>,
+    parent_types: Vec<Option<&'a str>>,
+}
+
+impl<'a> FieldPath<'a> {
+    pub fn new() -> FieldPath<'a> {
+        FieldPath::default()
+    }
+
+    /// Adds a field, selected on the given type, to the end of the path
+    pub fn push(&mut self, field: &'a Field, parent_type: Option<&'a str>) {
+        self.fields.push(field);
+        self.parent_types.push(parent_type);
+    }
+
+    /// Removes the last field from the path
+    pub fn pop(&mut self) {
+        self.fields.pop();
+        self.parent_types.pop();
+    }
+
+    pub fn fields(&self) -> &[&'a Field] {
+        &self.fields
+    }
+
+    /// Each field on the path, with the type it's selected on
+    fn iter(&self) -> impl Iterator<Item = (&'a Field, Option<&'a str>)> + '_ {
+        self.fields
+            .iter()
+            .copied()
+            .zip(self.parent_types.iter().copied())
+    }
+}
+
 impl fmt::Display for Pattern {
     // This trait requires `fmt` with this exact signature.
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -126,29 +253,246 @@ impl fmt::Display for Pattern {
             Pattern::Any => write!(f, "*"),
             Pattern::Query(ref field_pattern) => write!(f, "query:{}", field_pattern),
             Pattern::Mutation(ref field_pattern) => write!(f, "mutation:{}", field_pattern),
+            Pattern::Subscription(ref field_pattern) => {
+                write!(f, "subscription:{}", field_pattern)
+            }
+            Pattern::Type(ref type_pattern) => write!(f, "type:{}", type_pattern),
         }
     }
 }
 
-/// A FieldPattern matches a query or mutation field
-#[derive(Debug, Clone, PartialEq)]
-pub struct FieldPattern(String);
+/// A FieldPattern matches a query, mutation or subscription field, or a path of nested fields
+/// starting at a top-level field. A pattern is a dot-separated list of segments:
+///
+///   * a field name, where `*` matches any characters, e.g. `hero` or `get*`
+///   * `*` on its own, matches any single field
+///   * `**`, matches any number (including zero) of nested fields
+///
+/// For example, `hero.friends.name`, `hero.*.name` or `**.secretIdentity`. Each
+/// segment of a path has to match the whole field name, so `hero.secret` doesn't
+/// match `hero.secretIdentity`.
+///
+/// A pattern that's a single field name, with no dots, is matched as it always has
+/// been: as a regular expression (with `*` standing for `.*`) that can match anywhere
+/// in a top-level field's name. So `secret` matches `secretIdentity` as well.
+#[derive(Debug, Clone)]
+pub struct FieldPattern {
+    pattern: String,
+    segments: Vec<Segment>,
+}
+
+/// A segment of a FieldPattern, or a field of a TypePattern, compiled once
+#[derive(Debug, Clone)]
+enum Segment {
+    /// `**`, any number of nested fields
+    Descendants,
+    Name(Regex),
+}
+
+impl Segment {
+    /// A segment that has to match the whole field name, where `*` matches any
+    /// characters
+    fn parse(segment: &str) -> Result<Segment, regex::Error> {
+        if segment == "**" {
+            return Ok(Segment::Descendants);
+        }
+        let parts: Vec<String> = segment.split('*').map(regex::escape).collect();
+        Regex::new(&format!("^{}$", parts.join(".*"))).map(Segment::Name)
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Segment::Descendants => true,
+            Segment::Name(regex) => regex.is_match(name),
+        }
+    }
+}
 
 impl FieldPattern {
+    /// Compiles the given pattern string. Panics if a single field name isn't a
+    /// valid regular expression, see [parse](#method.parse).
+    pub fn new(pattern: &str) -> FieldPattern {
+        FieldPattern::parse(pattern).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Compiles the given pattern string, or returns an error if a single field name
+    /// isn't a valid regular expression
+    pub fn parse(pattern: &str) -> crate::Result<FieldPattern> {
+        let segments = if pattern.contains('.') {
+            pattern.split('.').map(Segment::parse).collect()
+        } else {
+            Regex::new(&pattern.replace('*', ".*")).map(|regex| vec![Segment::Name(regex)])
+        };
+        match segments {
+            Ok(segments) => Ok(FieldPattern {
+                pattern: pattern.into(),
+                segments,
+            }),
+            Err(err) => Err(ArboricError::general(format!(
+                "Invalid field pattern {:?}: {}",
+                pattern, err
+            ))),
+        }
+    }
+
+    /// Whether this pattern matches the given field, or a path of fields selected under it
     pub fn matches<F: Borrow<Field>>(&self, field: F) -> bool {
-        let FieldPattern(s) = self;
-        // TODO: compile Regex once
-        Regex::new(&s.replace("*", ".*"))
-            .unwrap()
-            .is_match(field.borrow().name.as_str())
+        matches_path(&self.segments, field.borrow())
+    }
+
+    /// Whether this pattern matches the given path of fields, starting at a top-level
+    /// field, as a whole or up to one of its fields
+    pub fn matches_path(&self, path: &[&Field]) -> bool {
+        !path.is_empty() && matches_field_path(&self.segments, path)
+    }
+}
+
+impl PartialEq for FieldPattern {
+    fn eq(&self, other: &FieldPattern) -> bool {
+        self.pattern == other.pattern
+    }
+}
+
+/// Whether the field, and the fields selected under it, match the path segments
+fn matches_path(segments: &[Segment], field: &Field) -> bool {
+    match segments.split_first() {
+        None => true,
+        Some((Segment::Descendants, rest)) => {
+            matches_path(rest, field)
+                || fields(&field.selection_set)
+                    .into_iter()
+                    .any(|child| matches_path(segments, child))
+        }
+        Some((segment, rest)) => {
+            segment.matches(&field.name)
+                && (rest.is_empty()
+                    || fields(&field.selection_set)
+                        .into_iter()
+                        .any(|child| matches_path(rest, child)))
+        }
+    }
+}
+
+/// Like [matches_path](matches_path), for a single (non-empty) path of fields
+fn matches_field_path(segments: &[Segment], path: &[&Field]) -> bool {
+    match segments.split_first() {
+        None => true,
+        Some((Segment::Descendants, rest)) => {
+            matches_field_path(rest, path)
+                || (path.len() > 1 && matches_field_path(segments, &path[1..]))
+        }
+        Some((segment, rest)) => {
+            segment.matches(&path[0].name)
+                && (rest.is_empty() || (path.len() > 1 && matches_field_path(rest, &path[1..])))
+        }
     }
 }
 
 impl fmt::Display for FieldPattern {
     // This trait requires `fmt` with this exact signature.
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let FieldPattern(ref s) = *self;
-        write!(f, "{}", &s)
+        write!(f, "{}", &self.pattern)
+    }
+}
+
+/// A TypePattern matches an operation that selects:
+///
+///   * any field that returns the type, e.g. `Hero`, or
+///   * one of the given fields on the type, e.g. `Hero{secretIdentity, name}`
+///
+/// wherever it's selected. Interfaces the type implements, and unions it belongs
+/// to, count as the type, since they could be resolved to it.
+#[derive(Debug, Clone)]
+pub struct TypePattern {
+    type_name: String,
+    fields: Vec<String>,
+    field_segments: Vec<Segment>,
+}
+
+impl TypePattern {
+    /// Parses the given TypePattern string, e.g. `Hero` or `Hero{secretIdentity, name}`
+    pub fn parse(s: &str) -> crate::Result<TypePattern> {
+        let (type_name, fields): (&str, Vec<String>) = match s.find('{') {
+            Some(i) => (
+                &s[..i],
+                s[i + 1..]
+                    .trim()
+                    .trim_end_matches('}')
+                    .split(',')
+                    .map(|field| field.trim().to_string())
+                    .filter(|field| !field.is_empty())
+                    .collect(),
+            ),
+            None => (s, Vec::new()),
+        };
+        let field_segments = fields
+            .iter()
+            .map(|field| Segment::parse(field))
+            .collect::<Result<Vec<Segment>, regex::Error>>()
+            .map_err(|err| {
+                ArboricError::general(format!("Invalid type pattern {:?}: {}", s, err))
+            })?;
+        Ok(TypePattern {
+            type_name: type_name.trim().into(),
+            fields,
+            field_segments,
+        })
+    }
+
+    pub fn matches(&self, operation_definition: &OperationDefinition, schema: &Schema) -> bool {
+        let is_type = |type_name: &str| {
+            schema.could_be(type_name, &self.type_name)
+                || schema.could_be(&self.type_name, type_name)
+        };
+        schema.any_field(operation_definition, |field, parent_type, field_type| {
+            if self.fields.is_empty() {
+                field_type.is_some_and(&is_type)
+            } else {
+                is_type(parent_type)
+                    && self
+                        .field_segments
+                        .iter()
+                        .any(|segment| segment.matches(&field.name))
+            }
+        })
+    }
+
+    /// Like [matches](#method.matches), for a single path of fields
+    pub fn matches_path(&self, path: &FieldPath, schema: &Schema) -> bool {
+        let is_type = |type_name: &str| {
+            schema.could_be(type_name, &self.type_name)
+                || schema.could_be(&self.type_name, type_name)
+        };
+        path.iter().any(|(field, parent_type)| match parent_type {
+            Some(parent_type) if self.fields.is_empty() => schema
+                .field_type(parent_type, &field.name)
+                .is_some_and(&is_type),
+            Some(parent_type) => {
+                is_type(parent_type)
+                    && self
+                        .field_segments
+                        .iter()
+                        .any(|segment| segment.matches(&field.name))
+            }
+            None => false,
+        })
+    }
+}
+
+impl PartialEq for TypePattern {
+    fn eq(&self, other: &TypePattern) -> bool {
+        self.type_name == other.type_name && self.fields == other.fields
+    }
+}
+
+impl fmt::Display for TypePattern {
+    // This trait requires `fmt` with this exact signature.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.fields.is_empty() {
+            write!(f, "{}", &self.type_name)
+        } else {
+            write!(f, "{}{{{}}}", &self.type_name, self.fields.join(","))
+        }
     }
 }
 
@@ -165,20 +509,24 @@ mod tests {
         crate::initialize_test_logging();
         assert_eq!(
             Pattern::parse("__type"),
-            Pattern::Query(FieldPattern("__type".into()))
+            Pattern::Query(FieldPattern::new("__type"))
         );
         assert_eq!(Pattern::parse("*"), Pattern::Any);
         assert_eq!(
             Pattern::parse("__schema"),
-            Pattern::Query(FieldPattern("__schema".into()))
+            Pattern::Query(FieldPattern::new("__schema"))
         );
         assert_eq!(
             Pattern::parse("query:*"),
-            Pattern::Query(FieldPattern("*".into()))
+            Pattern::Query(FieldPattern::new("*"))
         );
         assert_eq!(
             Pattern::parse("mutation:*"),
-            Pattern::Mutation(FieldPattern("*".into()))
+            Pattern::Mutation(FieldPattern::new("*"))
+        );
+        assert_eq!(
+            Pattern::parse("subscription:reviewAdded"),
+            Pattern::Subscription(FieldPattern::new("reviewAdded"))
         );
     }
 
@@ -202,10 +550,7 @@ mod tests {
 
     /// Just a convenience method to construct a [graphql_parser::Pos](graphql_parser::Pos) for testing
     fn pos(line: usize, column: usize) -> graphql_parser::Pos {
-        graphql_parser::Pos {
-            line: line,
-            column: column,
-        }
+        graphql_parser::Pos { line, column }
     }
 
     fn field(name: &str) -> Field {
@@ -223,10 +568,10 @@ mod tests {
     }
 
     fn query(s: &str) -> Field {
-        let doc = graphql_parser::parse_query(&s).unwrap();
-        match doc.definitions.iter().next().unwrap() {
+        let doc = graphql_parser::parse_query(s).unwrap();
+        match doc.definitions.first().unwrap() {
             Operation(OperationDefinition::SelectionSet(ref selection_set)) => {
-                let first = selection_set.items.iter().next().unwrap();
+                let first = selection_set.items.first().unwrap();
                 match first {
                     Selection::Field(field) => field.clone(),
                     x => panic!("Don't know what to do with {:?}!", x),
@@ -238,8 +583,159 @@ mod tests {
 
     #[test]
     fn test_field_pattern_matches() {
-        assert!(FieldPattern("*".into()).matches(field("foo")));
-        assert!(FieldPattern("foo".into()).matches(field("foo")));
-        assert!(FieldPattern("foo".into()).matches(query("{foo{id}}")));
+        assert!(FieldPattern::new("*").matches(field("foo")));
+        assert!(FieldPattern::new("foo").matches(field("foo")));
+        assert!(FieldPattern::new("foo").matches(query("{foo{id}}")));
+        assert!(FieldPattern::new("f*").matches(field("foo")));
+        // A single field name matches anywhere in the name, as it always has
+        assert!(FieldPattern::new("foo").matches(field("foobar")));
+        assert!(FieldPattern::new("secret").matches(field("secretIdentity")));
+        assert!(FieldPattern::new("^foo$").matches(field("foo")));
+        assert!(!FieldPattern::new("^foo$").matches(field("foobar")));
+    }
+
+    #[test]
+    fn test_field_pattern_parse() {
+        assert_eq!(FieldPattern::new("f*"), FieldPattern::parse("f*").unwrap());
+        match FieldPattern::parse("secret(") {
+            Err(ArboricError::GeneralError { message }) => {
+                assert!(message.starts_with(r#"Invalid field pattern "secret(""#))
+            }
+            x => panic!("Expected ArboricError::GeneralError, got {:?}!", x),
+        }
+        assert!(Pattern::try_parse("mutation:secret(").is_err());
+        assert!(FieldPattern::parse("hero.secret(").is_ok());
+    }
+
+    #[test]
+    fn test_field_pattern_matches_paths() {
+        crate::initialize_test_logging();
+        let hero =
+            query("{hero{name friends{name secretIdentity} ... on Droid {primaryFunction}}}");
+        assert!(FieldPattern::new("hero").matches(&hero));
+        assert!(FieldPattern::new("hero.name").matches(&hero));
+        assert!(FieldPattern::new("hero.friends.name").matches(&hero));
+        assert!(FieldPattern::new("hero.primaryFunction").matches(&hero));
+        assert!(!FieldPattern::new("hero.secretIdentity").matches(&hero));
+        // Path segments have to match the whole field name
+        assert!(!FieldPattern::new("hero.friends.secret").matches(&hero));
+        assert!(FieldPattern::new("hero.friends.secret*").matches(&hero));
+        assert!(!FieldPattern::new("her.name").matches(&hero));
+        assert!(!FieldPattern::new("hero.name.first").matches(&hero));
+        assert!(!FieldPattern::new("villain.name").matches(&hero));
+
+        assert!(FieldPattern::new("hero.*.name").matches(&hero));
+        assert!(!FieldPattern::new("*.secretIdentity").matches(&hero));
+        assert!(FieldPattern::new("*.*.secretIdentity").matches(&hero));
+
+        assert!(FieldPattern::new("**.secretIdentity").matches(&hero));
+        assert!(FieldPattern::new("**.hero").matches(&hero));
+        assert!(FieldPattern::new("hero.**.name").matches(&hero));
+        assert!(FieldPattern::new("hero.**").matches(&hero));
+        assert!(!FieldPattern::new("**.birthday").matches(&hero));
+        assert!(!FieldPattern::new("name.**").matches(&hero));
+    }
+
+    #[test]
+    fn test_field_pattern_matches_path() {
+        crate::initialize_test_logging();
+        let (hero, friends, name) = (field("hero"), field("friends"), field("name"));
+        let path = [&hero, &friends, &name];
+        assert!(FieldPattern::new("hero").matches_path(&path));
+        assert!(FieldPattern::new("hero.friends").matches_path(&path));
+        assert!(FieldPattern::new("hero.friends.name").matches_path(&path));
+        assert!(FieldPattern::new("hero.*.name").matches_path(&path));
+        assert!(FieldPattern::new("**.name").matches_path(&path));
+        assert!(FieldPattern::new("hero.**").matches_path(&path));
+        assert!(!FieldPattern::new("hero.name").matches_path(&path));
+        assert!(!FieldPattern::new("hero.friends.name.first").matches_path(&path));
+        assert!(!FieldPattern::new("hero.**").matches_path(&[&hero]));
+        assert!(!FieldPattern::new("*").matches_path(&[]));
+    }
+
+    #[test]
+    fn test_type_pattern_matches() {
+        crate::initialize_test_logging();
+        let schema = Schema::parse(crate::graphql::test_support::STAR_WARS).unwrap();
+        let matches = |pattern: &str, query: &str| {
+            let doc = graphql_parser::parse_query(query).unwrap();
+            match doc.definitions.first() {
+                Some(Operation(od)) => {
+                    Pattern::parse(pattern).matches_with_schema(od, Some(&schema))
+                }
+                x => panic!("Don't know what to do with {:?}!", x),
+            }
+        };
+        assert_eq!(
+            Pattern::parse("type: Human { secretIdentity , name }").to_string(),
+            "type:Human{secretIdentity,name}"
+        );
+        assert!(matches("type:Human", "{human(id: 1){name}}"));
+        // A Character could be a Human
+        assert!(matches("type:Human", "{hero{name}}"));
+        assert!(matches("type:Character", "{human(id: 1){name}}"));
+        assert!(matches(
+            "type:Human",
+            "{search(text: \"Luke\"){__typename}}"
+        ));
+        assert!(!matches("type:Review", "{hero{name}}"));
+        assert!(matches(
+            "type:Review",
+            "mutation {createReview(episode: JEDI, stars: 5){stars}}"
+        ));
+
+        assert!(matches(
+            "type:Human{secretIdentity}",
+            "{human(id: 1){secretIdentity}}"
+        ));
+        assert!(matches(
+            "type:Human{secretIdentity}",
+            "{hero{friends{... on Human {secretIdentity}}}}"
+        ));
+        assert!(matches(
+            "type:Human{secret*}",
+            "{search(text: \"Luke\"){... on Human {secretIdentity}}}"
+        ));
+        assert!(!matches(
+            "type:Human{secretIdentity}",
+            "{human(id: 1){name}}"
+        ));
+        assert!(!matches("type:Droid{name}", "{human(id: 1){name}}"));
+        assert!(matches("type:Query{hero}", "{hero{name}}"));
+
+        let doc = graphql_parser::parse_query("{human(id: 1){name}}").unwrap();
+        if let Some(Operation(od)) = doc.definitions.first() {
+            assert!(!Pattern::parse("type:Human").matches(od));
+        }
+    }
+
+    #[test]
+    fn test_pattern_matches_subscriptions() {
+        crate::initialize_test_logging();
+        let doc = graphql_parser::parse_query("subscription { reviewAdded { stars } }").unwrap();
+        if let Some(Operation(od)) = doc.definitions.first() {
+            assert!(Pattern::parse("*").matches(od));
+            assert!(Pattern::parse("subscription:*").matches(od));
+            assert!(Pattern::parse("subscription:review*").matches(od));
+            assert!(!Pattern::parse("query:*").matches(od));
+            assert!(!Pattern::parse("mutation:*").matches(od));
+        } else {
+            panic!("Expected Definition::Operation, got {:?}!", &doc);
+        }
+    }
+
+    #[test]
+    fn test_pattern_matches_nested_fields() {
+        crate::initialize_test_logging();
+        let doc =
+            graphql_parser::parse_query("query { hero { friends { secretIdentity } } }").unwrap();
+        if let Some(Operation(od)) = doc.definitions.first() {
+            assert!(Pattern::parse("hero.friends.secretIdentity").matches(od));
+            assert!(Pattern::parse("query:**.secretIdentity").matches(od));
+            assert!(!Pattern::parse("hero.secretIdentity").matches(od));
+            assert!(!Pattern::parse("mutation:**.secretIdentity").matches(od));
+        } else {
+            panic!("Expected Definition::Operation, got {:?}!", &doc);
+        }
     }
 }
@@ -0,0 +1,383 @@
+//! A graphql::Schema is the upstream API's schema, loaded from its SDL, which lets
+//! Arboric resolve the type of each field a query selects
+
+use crate::ArboricError;
+use graphql_parser::query::{OperationDefinition, Selection, SelectionSet, TypeCondition};
+use graphql_parser::schema::{
+    parse_schema, Definition, Field, Type, TypeDefinition, TypeExtension,
+};
+use log::{trace, warn};
+use std::collections::HashMap;
+
+/// A graphql::Schema holds the type definitions of an upstream API, with any type
+/// extensions merged in
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schema {
+    query: String,
+    mutation: Option<String>,
+    subscription: Option<String>,
+    types: HashMap<String, TypeDefinition>,
+}
+
+impl Schema {
+    /// Parses the given SDL string
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use arboric::graphql::Schema;
+    ///
+    /// let schema = Schema::parse("type Query { hero: Hero } type Hero { name: String }").unwrap();
+    /// assert_eq!(Some("Hero"), schema.field_type("Query", "hero"));
+    /// assert_eq!(Some("String"), schema.field_type("Hero", "name"));
+    /// ```
+    pub fn parse(sdl: &str) -> crate::Result<Schema> {
+        let document = parse_schema(sdl)?;
+        let mut schema = Schema {
+            query: "Query".into(),
+            mutation: None,
+            subscription: None,
+            types: HashMap::new(),
+        };
+        let mut extensions = Vec::new();
+        let mut has_schema_definition = false;
+        for definition in document.definitions {
+            match definition {
+                Definition::SchemaDefinition(schema_definition) => {
+                    has_schema_definition = true;
+                    if let Some(query) = schema_definition.query {
+                        schema.query = query;
+                    }
+                    schema.mutation = schema_definition.mutation;
+                    schema.subscription = schema_definition.subscription;
+                }
+                Definition::TypeDefinition(type_definition) => {
+                    schema
+                        .types
+                        .insert(type_name(&type_definition).to_string(), type_definition);
+                }
+                Definition::TypeExtension(type_extension) => extensions.push(type_extension),
+                Definition::DirectiveDefinition(_) => (),
+            }
+        }
+        for type_extension in extensions {
+            schema.extend(type_extension)?;
+        }
+        // Without a schema definition, the root types are named by convention
+        if !has_schema_definition {
+            if schema.types.contains_key("Mutation") {
+                schema.mutation = Some("Mutation".into());
+            }
+            if schema.types.contains_key("Subscription") {
+                schema.subscription = Some("Subscription".into());
+            }
+        }
+        if !schema.types.contains_key(&schema.query) {
+            return Err(ArboricError::general(format!(
+                r#"Schema has no query type "{}""#,
+                schema.query
+            )));
+        }
+        trace!("schema => {:?}", schema.types.keys());
+        Ok(schema)
+    }
+
+    /// Reads and parses the given SDL file
+    pub fn from_file(filename: &str) -> crate::Result<Schema> {
+        let sdl = std::fs::read_to_string(filename).map_err(|cause| ArboricError::IoError {
+            message: format!("Unable to read schema from {}: {}", filename, cause),
+            cause,
+        })?;
+        Schema::parse(&sdl)
+    }
+
+    fn extend(&mut self, type_extension: TypeExtension) -> crate::Result<()> {
+        let name = match type_extension {
+            TypeExtension::Scalar(ref extension) => &extension.name,
+            TypeExtension::Object(ref extension) => &extension.name,
+            TypeExtension::Interface(ref extension) => &extension.name,
+            TypeExtension::Union(ref extension) => &extension.name,
+            TypeExtension::Enum(ref extension) => &extension.name,
+            TypeExtension::InputObject(ref extension) => &extension.name,
+        }
+        .clone();
+        match (self.types.get_mut(&name), type_extension) {
+            (Some(TypeDefinition::Scalar(_)), TypeExtension::Scalar(_)) => (),
+            (Some(TypeDefinition::Object(object)), TypeExtension::Object(extension)) => {
+                object.fields.extend(extension.fields);
+                object
+                    .implements_interfaces
+                    .extend(extension.implements_interfaces);
+            }
+            (Some(TypeDefinition::Interface(interface)), TypeExtension::Interface(extension)) => {
+                interface.fields.extend(extension.fields)
+            }
+            (Some(TypeDefinition::Union(union)), TypeExtension::Union(extension)) => {
+                union.types.extend(extension.types)
+            }
+            (Some(TypeDefinition::Enum(enum_type)), TypeExtension::Enum(extension)) => {
+                enum_type.values.extend(extension.values)
+            }
+            (Some(TypeDefinition::InputObject(input)), TypeExtension::InputObject(extension)) => {
+                input.fields.extend(extension.fields)
+            }
+            _ => {
+                return Err(ArboricError::general(format!(
+                    r#"Can't extend type "{}", it's undefined or of a different kind"#,
+                    name
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// The type definition with the given name
+    pub fn get_type(&self, name: &str) -> Option<&TypeDefinition> {
+        self.types.get(name)
+    }
+
+    /// The name of the query root type, usually `Query`
+    pub fn query_type(&self) -> &str {
+        &self.query
+    }
+
+    /// The name of the root type of the given operation, if the schema has one
+    pub fn root_type(&self, operation_definition: &OperationDefinition) -> Option<&str> {
+        match operation_definition {
+            OperationDefinition::SelectionSet(_) | OperationDefinition::Query(_) => {
+                Some(self.query.as_str())
+            }
+            OperationDefinition::Mutation(_) => self.mutation.as_deref(),
+            OperationDefinition::Subscription(_) => self.subscription.as_deref(),
+        }
+    }
+
+    /// The definition of the named field of an object or interface type
+    pub fn field(&self, type_name: &str, field_name: &str) -> Option<&Field> {
+        let fields = match self.types.get(type_name) {
+            Some(TypeDefinition::Object(object)) => &object.fields,
+            Some(TypeDefinition::Interface(interface)) => &interface.fields,
+            _ => return None,
+        };
+        fields.iter().find(|field| field.name == field_name)
+    }
+
+    /// The name of the type the named field returns, without any list or non-null
+    /// wrappers. Knows about `__typename`, but not the other introspection fields.
+    pub fn field_type(&self, type_name: &str, field_name: &str) -> Option<&str> {
+        if field_name == "__typename" {
+            return Some("String");
+        }
+        self.field(type_name, field_name)
+            .map(|field| named_type(&field.field_type))
+    }
+
+    /// Whether a value of the given type could be of the named concrete type, i.e. if
+    /// they're the same type, or `type_name` is an interface it implements or a union
+    /// it belongs to
+    pub fn could_be(&self, type_name: &str, concrete_type: &str) -> bool {
+        if type_name == concrete_type {
+            return true;
+        }
+        match (self.types.get(type_name), self.types.get(concrete_type)) {
+            (Some(TypeDefinition::Interface(_)), Some(TypeDefinition::Object(object))) => object
+                .implements_interfaces
+                .iter()
+                .any(|interface| interface == type_name),
+            (Some(TypeDefinition::Union(union)), _) => {
+                union.types.iter().any(|member| member == concrete_type)
+            }
+            _ => false,
+        }
+    }
+
+    /// The object types a value of the given type could be: the type itself if it's an
+    /// object type, the object types implementing an interface, or the members of a union
+    pub fn possible_types(&self, type_name: &str) -> Vec<&str> {
+        match self.types.get(type_name) {
+            Some(TypeDefinition::Object(object)) => vec![object.name.as_str()],
+            Some(TypeDefinition::Interface(_)) => self
+                .types
+                .values()
+                .filter_map(|type_definition| match type_definition {
+                    TypeDefinition::Object(object)
+                        if object
+                            .implements_interfaces
+                            .iter()
+                            .any(|interface| interface == type_name) =>
+                    {
+                        Some(object.name.as_str())
+                    }
+                    _ => None,
+                })
+                .collect(),
+            Some(TypeDefinition::Union(union)) => union.types.iter().map(String::as_str).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Whether a value could be of both types at once, e.g. an interface and a union
+    /// that has a member implementing it
+    pub fn overlaps(&self, a: &str, b: &str) -> bool {
+        let possible_types = self.possible_types(b);
+        self.possible_types(a)
+            .iter()
+            .any(|type_name| possible_types.contains(type_name))
+    }
+
+    /// Visits each field the operation selects, at any depth, with the type it's
+    /// selected on and the type it returns (if known), until `f` returns `true`.
+    /// Fragment spreads should be expanded first.
+    pub fn any_field<F>(&self, operation_definition: &OperationDefinition, mut f: F) -> bool
+    where
+        F: FnMut(&graphql_parser::query::Field, &str, Option<&str>) -> bool,
+    {
+        let selection_set = match operation_definition {
+            OperationDefinition::SelectionSet(selection_set) => selection_set,
+            OperationDefinition::Query(query) => &query.selection_set,
+            OperationDefinition::Mutation(mutation) => &mutation.selection_set,
+            OperationDefinition::Subscription(subscription) => &subscription.selection_set,
+        };
+        match self.root_type(operation_definition) {
+            Some(root_type) => self.any_field_in(selection_set, root_type, &mut f),
+            None => {
+                warn!("Schema has no root type for {:?}", operation_definition);
+                false
+            }
+        }
+    }
+
+    fn any_field_in<F>(&self, selection_set: &SelectionSet, parent_type: &str, f: &mut F) -> bool
+    where
+        F: FnMut(&graphql_parser::query::Field, &str, Option<&str>) -> bool,
+    {
+        selection_set.items.iter().any(|selection| match selection {
+            Selection::Field(field) => {
+                let field_type = self.field_type(parent_type, &field.name);
+                f(field, parent_type, field_type)
+                    || field_type.is_some_and(|field_type| {
+                        self.any_field_in(&field.selection_set, field_type, f)
+                    })
+            }
+            Selection::InlineFragment(inline_fragment) => {
+                let parent_type = match inline_fragment.type_condition {
+                    Some(TypeCondition::On(ref type_name)) => type_name.as_str(),
+                    None => parent_type,
+                };
+                self.any_field_in(&inline_fragment.selection_set, parent_type, f)
+            }
+            Selection::FragmentSpread(spread) => {
+                warn!("Unexpanded fragment spread {:?}", spread.fragment_name);
+                false
+            }
+        })
+    }
+}
+
+/// The name of a type definition
+pub fn type_name(type_definition: &TypeDefinition) -> &str {
+    match type_definition {
+        TypeDefinition::Scalar(scalar) => &scalar.name,
+        TypeDefinition::Object(object) => &object.name,
+        TypeDefinition::Interface(interface) => &interface.name,
+        TypeDefinition::Union(union) => &union.name,
+        TypeDefinition::Enum(enum_type) => &enum_type.name,
+        TypeDefinition::InputObject(input) => &input.name,
+    }
+}
+
+/// The name of the type, without any list or non-null wrappers
+pub fn named_type(t: &Type) -> &str {
+    match t {
+        Type::NamedType(name) => name,
+        Type::ListType(t) | Type::NonNullType(t) => named_type(t),
+    }
+}
+
+/// A schema for tests
+#[cfg(test)]
+pub mod test_support {
+    pub const STAR_WARS: &str = r#"
+        schema { query: Query mutation: Mutation }
+        type Query {
+            hero(episode: Episode): Character
+            human(id: ID!): Human
+            search(text: String!): [SearchResult!]!
+        }
+        type Mutation { createReview(episode: Episode!, stars: Int!): Review }
+        enum Episode { NEWHOPE EMPIRE JEDI }
+        interface Character { id: ID! name: String! friends: [Character] }
+        type Human implements Character {
+            id: ID!
+            name: String!
+            friends: [Character]
+            secretIdentity: String
+        }
+        type Droid implements Character { id: ID! name: String! friends: [Character] }
+        union SearchResult = Human | Droid
+        type Review { stars: Int! }
+        extend type Human { homePlanet: String }
+    "#;
+}
+
+#[cfg(test)]
+mod tests {
+    // Import names from outer (for mod tests) scope.
+    use super::*;
+
+    use super::test_support::STAR_WARS;
+
+    #[test]
+    fn test_schema_parse() {
+        crate::initialize_test_logging();
+        let schema = Schema::parse(STAR_WARS).unwrap();
+        assert_eq!(Some("Character"), schema.field_type("Query", "hero"));
+        assert_eq!(Some("SearchResult"), schema.field_type("Query", "search"));
+        assert_eq!(Some("String"), schema.field_type("Human", "homePlanet"));
+        assert_eq!(Some("String"), schema.field_type("Droid", "__typename"));
+        assert_eq!(None, schema.field_type("Droid", "secretIdentity"));
+        assert!(schema.could_be("Character", "Human"));
+        assert!(schema.could_be("SearchResult", "Droid"));
+        assert!(!schema.could_be("Droid", "Human"));
+        assert!(schema.overlaps("Character", "SearchResult"));
+        assert!(schema.overlaps("Human", "Character"));
+        assert!(!schema.overlaps("Human", "Droid"));
+
+        assert!(Schema::parse("type Hero { name: String }").is_err());
+        assert!(Schema::parse("type Query { a: Int } extend type Hero { b: Int }").is_err());
+    }
+
+    #[test]
+    fn test_schema_any_field() {
+        crate::initialize_test_logging();
+        let schema = Schema::parse(STAR_WARS).unwrap();
+        let document = graphql_parser::parse_query(
+            "{ hero { name friends { ... on Human { secretIdentity } } } }",
+        )
+        .unwrap();
+        if let Some(graphql_parser::query::Definition::Operation(od)) = document.definitions.first()
+        {
+            let mut visited = Vec::new();
+            assert!(!schema.any_field(od, |field, parent_type, field_type| {
+                visited.push(format!(
+                    "{}.{}: {}",
+                    parent_type,
+                    field.name,
+                    field_type.unwrap_or("?")
+                ));
+                false
+            }));
+            assert_eq!(
+                vec![
+                    "Query.hero: Character",
+                    "Character.name: String",
+                    "Character.friends: Character",
+                    "Human.secretIdentity: String",
+                ],
+                visited
+            );
+        } else {
+            panic!("Expected Definition::Operation, got {:?}!", &document);
+        }
+    }
+}
@@ -0,0 +1,537 @@
+//! Validates GraphQL documents against the upstream API's schema, so that invalid
+//! operations are refused before they're counted, evaluated or forwarded
+
+use super::operations::operation_type;
+use super::schema::named_type;
+use super::Schema;
+use graphql_parser::query::{
+    Definition, Document, Field, OperationDefinition, Selection, SelectionSet, Type, TypeCondition,
+    Value, VariableDefinition,
+};
+use graphql_parser::schema::TypeDefinition;
+use log::trace;
+use std::collections::HashMap;
+
+/// Validates the (fragment expanded) document against the schema, given the request
+/// variables. Returns a message for each problem found, so an empty list means the
+/// document is valid. Checks that:
+///
+/// * the schema supports each operation type
+/// * each selected field exists on its type, and has a selection of subfields if,
+///   and only if, it returns an object, interface or union
+/// * each argument exists, required arguments are given, and literal values and
+///   variables have the expected type
+/// * each variable used is defined, its type is allowed where it's used, and
+///   required variables are provided
+/// * each inline fragment's type condition exists, and could apply where it's used
+///
+/// The introspection fields `__schema` and `__type` aren't validated.
+pub fn validate(
+    schema: &Schema,
+    document: &Document,
+    variables: &HashMap<String, serde_json::Value>,
+) -> Vec<String> {
+    let mut errors = Vec::new();
+    for definition in document.definitions.iter() {
+        match definition {
+            Definition::Operation(operation_definition) => {
+                let (variable_definitions, selection_set): (&[VariableDefinition], _) =
+                    match operation_definition {
+                        OperationDefinition::SelectionSet(selection_set) => (&[], selection_set),
+                        OperationDefinition::Query(query) => {
+                            (query.variable_definitions.as_slice(), &query.selection_set)
+                        }
+                        OperationDefinition::Mutation(mutation) => (
+                            mutation.variable_definitions.as_slice(),
+                            &mutation.selection_set,
+                        ),
+                        OperationDefinition::Subscription(subscription) => (
+                            subscription.variable_definitions.as_slice(),
+                            &subscription.selection_set,
+                        ),
+                    };
+                let mut validator = Validator {
+                    schema,
+                    variable_definitions,
+                    errors: &mut errors,
+                };
+                validator.validate_variables(variables);
+                match schema.root_type(operation_definition) {
+                    Some(root_type) => validator.validate_selection_set(selection_set, root_type),
+                    None => validator.errors.push(format!(
+                        "Schema is not configured for {}s.",
+                        operation_type(operation_definition)
+                    )),
+                }
+            }
+            Definition::Fragment(fragment_definition) => errors.push(format!(
+                r#"Fragment "{}" was not expanded."#,
+                fragment_definition.name
+            )),
+        }
+    }
+    trace!("validate() => {:?}", &errors);
+    errors
+}
+
+/// Holds what's needed to validate one operation
+struct Validator<'a> {
+    schema: &'a Schema,
+    variable_definitions: &'a [VariableDefinition],
+    errors: &'a mut Vec<String>,
+}
+
+impl<'a> Validator<'a> {
+    fn validate_variables(&mut self, variables: &HashMap<String, serde_json::Value>) {
+        for variable_definition in self.variable_definitions.iter() {
+            let type_name = named_type(&variable_definition.var_type);
+            match self.schema.get_type(type_name) {
+                Some(TypeDefinition::Scalar(_))
+                | Some(TypeDefinition::Enum(_))
+                | Some(TypeDefinition::InputObject(_)) => (),
+                Some(_) => self.errors.push(format!(
+                    r#"Variable "${}" cannot be non-input type "{}"."#,
+                    variable_definition.name, variable_definition.var_type
+                )),
+                None if is_builtin_scalar(type_name) => (),
+                None => self
+                    .errors
+                    .push(format!(r#"Unknown type "{}"."#, type_name)),
+            }
+            let required = match variable_definition.var_type {
+                Type::NonNullType(_) => variable_definition.default_value.is_none(),
+                _ => false,
+            };
+            let provided = match variables.get(&variable_definition.name) {
+                None | Some(serde_json::Value::Null) => false,
+                Some(_) => true,
+            };
+            if required && !provided {
+                self.errors.push(format!(
+                    r#"Variable "${}" of required type "{}" was not provided."#,
+                    variable_definition.name, variable_definition.var_type
+                ));
+            }
+        }
+    }
+
+    fn validate_selection_set(&mut self, selection_set: &SelectionSet, parent_type: &str) {
+        for selection in selection_set.items.iter() {
+            match selection {
+                Selection::Field(field) => self.validate_field(field, parent_type),
+                Selection::InlineFragment(inline_fragment) => {
+                    match inline_fragment.type_condition {
+                        Some(TypeCondition::On(ref type_name)) => {
+                            if self.validate_type_condition(type_name, parent_type) {
+                                self.validate_selection_set(
+                                    &inline_fragment.selection_set,
+                                    type_name,
+                                );
+                            }
+                        }
+                        None => {
+                            self.validate_selection_set(&inline_fragment.selection_set, parent_type)
+                        }
+                    }
+                }
+                Selection::FragmentSpread(spread) => self.errors.push(format!(
+                    r#"Fragment "{}" was not expanded."#,
+                    spread.fragment_name
+                )),
+            }
+        }
+    }
+
+    /// Returns whether the type condition is valid, and so its selections can be validated
+    fn validate_type_condition(&mut self, type_name: &str, parent_type: &str) -> bool {
+        match self.schema.get_type(type_name) {
+            Some(TypeDefinition::Object(_))
+            | Some(TypeDefinition::Interface(_))
+            | Some(TypeDefinition::Union(_)) => {
+                if self.schema.overlaps(type_name, parent_type) {
+                    true
+                } else {
+                    self.errors.push(format!(
+                        r#"Fragment cannot be spread here as objects of type "{}" can never be of type "{}"."#,
+                        parent_type, type_name
+                    ));
+                    false
+                }
+            }
+            Some(_) => {
+                self.errors.push(format!(
+                    r#"Fragment cannot condition on non composite type "{}"."#,
+                    type_name
+                ));
+                false
+            }
+            None => {
+                self.errors
+                    .push(format!(r#"Unknown type "{}"."#, type_name));
+                false
+            }
+        }
+    }
+
+    fn validate_field(&mut self, field: &Field, parent_type: &str) {
+        trace!("validate_field({:?}, {})", &field.name, parent_type);
+        let schema = self.schema;
+        let introspection = match field.name.as_str() {
+            "__typename" => true,
+            "__schema" | "__type" => parent_type == schema.query_type(),
+            _ => false,
+        };
+        if introspection {
+            return;
+        }
+        let field_definition = match schema.field(parent_type, &field.name) {
+            Some(field_definition) => field_definition,
+            None => {
+                self.errors.push(format!(
+                    r#"Cannot query field "{}" on type "{}"."#,
+                    field.name, parent_type
+                ));
+                return;
+            }
+        };
+        self.validate_arguments(field, field_definition, parent_type);
+
+        let field_type = named_type(&field_definition.field_type);
+        let has_subfields = matches!(
+            schema.get_type(field_type),
+            Some(TypeDefinition::Object(_))
+                | Some(TypeDefinition::Interface(_))
+                | Some(TypeDefinition::Union(_))
+        );
+        if has_subfields && field.selection_set.items.is_empty() {
+            self.errors.push(format!(
+                r#"Field "{}" of type "{}" must have a selection of subfields."#,
+                field.name, field_definition.field_type
+            ));
+        } else if !has_subfields && !field.selection_set.items.is_empty() {
+            self.errors.push(format!(
+                r#"Field "{}" must not have a selection since type "{}" has no subfields."#,
+                field.name, field_definition.field_type
+            ));
+        } else if has_subfields {
+            self.validate_selection_set(&field.selection_set, field_type);
+        }
+    }
+
+    fn validate_arguments(
+        &mut self,
+        field: &Field,
+        field_definition: &graphql_parser::schema::Field,
+        parent_type: &str,
+    ) {
+        for (name, value) in field.arguments.iter() {
+            match field_definition
+                .arguments
+                .iter()
+                .find(|argument| &argument.name == name)
+            {
+                Some(argument) => {
+                    if !self.is_valid_value(value, &argument.value_type) {
+                        self.errors.push(format!(
+                            r#"Argument "{}" on field "{}.{}" has invalid value {}, expected type "{}"."#,
+                            name, parent_type, field.name, value, argument.value_type
+                        ));
+                    }
+                }
+                None => self.errors.push(format!(
+                    r#"Unknown argument "{}" on field "{}.{}"."#,
+                    name, parent_type, field.name
+                )),
+            }
+        }
+        for argument in field_definition.arguments.iter() {
+            let required = match argument.value_type {
+                Type::NonNullType(_) => argument.default_value.is_none(),
+                _ => false,
+            };
+            if required
+                && !field
+                    .arguments
+                    .iter()
+                    .any(|(name, _)| name == &argument.name)
+            {
+                self.errors.push(format!(
+                    r#"Field "{}.{}" argument "{}" of type "{}" is required, but it was not provided."#,
+                    parent_type, field.name, argument.name, argument.value_type
+                ));
+            }
+        }
+    }
+
+    /// Whether the value could be coerced to the expected input type. Reports undefined,
+    /// and mismatched, variables separately.
+    fn is_valid_value(&mut self, value: &Value, expected_type: &Type) -> bool {
+        if let Value::Variable(name) = value {
+            match self
+                .variable_definitions
+                .iter()
+                .find(|variable_definition| &variable_definition.name == name)
+            {
+                Some(variable_definition) => {
+                    // A nullable variable with a default can be used where a value is
+                    // required, since the default is used when it isn't provided
+                    let location_type = match expected_type {
+                        Type::NonNullType(inner_type) => match variable_definition.default_value {
+                            Some(Value::Null) | None => expected_type,
+                            Some(_) => inner_type,
+                        },
+                        _ => expected_type,
+                    };
+                    if !is_allowed_variable_type(&variable_definition.var_type, location_type) {
+                        self.errors.push(format!(
+                            r#"Variable "${}" of type "{}" used in position expecting type "{}"."#,
+                            name, variable_definition.var_type, expected_type
+                        ));
+                    }
+                }
+                None => self
+                    .errors
+                    .push(format!(r#"Variable "${}" is not defined."#, name)),
+            }
+            return true;
+        }
+        match (expected_type, value) {
+            (Type::NonNullType(_), Value::Null) => false,
+            (Type::NonNullType(inner_type), _) => self.is_valid_value(value, inner_type),
+            (_, Value::Null) => true,
+            (Type::ListType(inner_type), Value::List(values)) => values
+                .iter()
+                .all(|value| self.is_valid_value(value, inner_type)),
+            // A single value is coerced to a list of one
+            (Type::ListType(inner_type), _) => self.is_valid_value(value, inner_type),
+            (Type::NamedType(type_name), _) => self.is_valid_named_value(value, type_name),
+        }
+    }
+
+    fn is_valid_named_value(&mut self, value: &Value, type_name: &str) -> bool {
+        let schema = self.schema;
+        match (type_name, value) {
+            ("Int", Value::Int(n)) => n
+                .as_i64()
+                .is_some_and(|n| n >= i32::MIN as i64 && n <= i32::MAX as i64),
+            ("Float", Value::Int(_)) | ("Float", Value::Float(_)) => true,
+            ("String", Value::String(_)) => true,
+            ("Boolean", Value::Boolean(_)) => true,
+            ("ID", Value::String(_)) | ("ID", Value::Int(_)) => true,
+            _ if is_builtin_scalar(type_name) => false,
+            _ => match schema.get_type(type_name) {
+                Some(TypeDefinition::Enum(enum_type)) => match value {
+                    Value::Enum(name) => enum_type.values.iter().any(|v| &v.name == name),
+                    _ => false,
+                },
+                // Custom scalars accept any literal
+                Some(TypeDefinition::Scalar(_)) => true,
+                Some(TypeDefinition::InputObject(input_object)) => match value {
+                    Value::Object(fields) => {
+                        let input_fields = &input_object.fields;
+                        fields.iter().all(|(name, value)| {
+                            match input_fields.iter().find(|field| &field.name == name) {
+                                Some(input_field) => {
+                                    self.is_valid_value(value, &input_field.value_type)
+                                }
+                                None => false,
+                            }
+                        }) && input_fields
+                            .iter()
+                            .all(|input_field| match input_field.value_type {
+                                Type::NonNullType(_) if input_field.default_value.is_none() => {
+                                    fields.contains_key(&input_field.name)
+                                }
+                                _ => true,
+                            })
+                    }
+                    _ => false,
+                },
+                _ => false,
+            },
+        }
+    }
+}
+
+/// Whether a variable of the given type can be used where a value of the location
+/// type is expected: the named types must match, a list only fills a list, and a
+/// nullable variable can't fill a required (non-null) position
+fn is_allowed_variable_type(variable_type: &Type, location_type: &Type) -> bool {
+    match (variable_type, location_type) {
+        (Type::NonNullType(variable_type), Type::NonNullType(location_type)) => {
+            is_allowed_variable_type(variable_type, location_type)
+        }
+        (Type::NonNullType(variable_type), _) => {
+            is_allowed_variable_type(variable_type, location_type)
+        }
+        (_, Type::NonNullType(_)) => false,
+        (Type::ListType(variable_type), Type::ListType(location_type)) => {
+            is_allowed_variable_type(variable_type, location_type)
+        }
+        (Type::NamedType(variable_type), Type::NamedType(location_type)) => {
+            variable_type == location_type
+        }
+        _ => false,
+    }
+}
+
+fn is_builtin_scalar(type_name: &str) -> bool {
+    matches!(type_name, "Int" | "Float" | "String" | "Boolean" | "ID")
+}
+
+#[cfg(test)]
+mod tests {
+    // Import names from outer (for mod tests) scope.
+    use super::*;
+
+    use crate::graphql::test_support::STAR_WARS;
+    use serde_json::json;
+
+    fn errors(query: &str, variables: serde_json::Value) -> Vec<String> {
+        let schema = Schema::parse(STAR_WARS).unwrap();
+        let document =
+            crate::graphql::expand_fragments(&graphql_parser::parse_query(query).unwrap()).unwrap();
+        let variables: HashMap<String, serde_json::Value> =
+            serde_json::from_value(variables).unwrap();
+        validate(&schema, &document, &variables)
+    }
+
+    #[test]
+    fn test_validate_valid_documents() {
+        crate::initialize_test_logging();
+        let none: Vec<String> = Vec::new();
+        assert_eq!(none, errors("{hero{name __typename}}", json!({})));
+        assert_eq!(
+            none,
+            errors(
+                "query Hero($episode: Episode) {hero(episode: $episode){...F}} fragment F on Character {friends{name ... on Human {secretIdentity}}}",
+                json!({"episode": "JEDI"})
+            )
+        );
+        assert_eq!(
+            none,
+            errors(
+                "mutation($stars: Int!) {createReview(episode: JEDI, stars: $stars){stars}}",
+                json!({"stars": 5})
+            )
+        );
+        assert_eq!(
+            none,
+            errors("{search(text: \"R2\"){... on Droid {name}}}", json!({}))
+        );
+        assert_eq!(none, errors("{__schema{types{name}}}", json!({})));
+    }
+
+    #[test]
+    fn test_validate_fields() {
+        crate::initialize_test_logging();
+        assert_eq!(
+            vec![r#"Cannot query field "secretIdentity" on type "Character"."#],
+            errors("{hero{secretIdentity}}", json!({}))
+        );
+        assert_eq!(
+            vec![r#"Field "hero" of type "Character" must have a selection of subfields."#],
+            errors("{hero}", json!({}))
+        );
+        assert_eq!(
+            vec![
+                r#"Field "name" must not have a selection since type "String!" has no subfields."#
+            ],
+            errors("{hero{name{first}}}", json!({}))
+        );
+        assert_eq!(
+            vec!["Schema is not configured for subscriptions."],
+            errors("subscription {reviews{stars}}", json!({}))
+        );
+    }
+
+    #[test]
+    fn test_validate_arguments() {
+        crate::initialize_test_logging();
+        assert_eq!(
+            vec![
+                r#"Argument "episode" on field "Query.hero" has invalid value SEQUELS, expected type "Episode"."#
+            ],
+            errors("{hero(episode: SEQUELS){name}}", json!({}))
+        );
+        assert_eq!(
+            vec![
+                r#"Argument "stars" on field "Mutation.createReview" has invalid value "five", expected type "Int!"."#
+            ],
+            errors(
+                "mutation {createReview(episode: JEDI, stars: \"five\"){stars}}",
+                json!({})
+            )
+        );
+        assert_eq!(
+            vec![
+                r#"Field "Query.human" argument "id" of type "ID!" is required, but it was not provided."#
+            ],
+            errors("{human{name}}", json!({}))
+        );
+        assert_eq!(
+            vec![r#"Unknown argument "first" on field "Query.hero"."#],
+            errors("{hero(first: 10){name}}", json!({}))
+        );
+    }
+
+    #[test]
+    fn test_validate_variables() {
+        crate::initialize_test_logging();
+        assert_eq!(
+            vec![r#"Variable "$id" of required type "ID!" was not provided."#],
+            errors("query($id: ID!) {human(id: $id){name}}", json!({}))
+        );
+        assert_eq!(
+            vec![r#"Variable "$id" is not defined."#],
+            errors("{human(id: $id){name}}", json!({}))
+        );
+        assert_eq!(
+            vec![r#"Variable "$id" of type "Int" used in position expecting type "ID!"."#],
+            errors("query($id: Int) {human(id: $id){name}}", json!({"id": 1}))
+        );
+        assert_eq!(
+            vec![r#"Variable "$id" of type "ID" used in position expecting type "ID!"."#],
+            errors("query($id: ID) {human(id: $id){name}}", json!({"id": 1}))
+        );
+        assert_eq!(
+            Vec::<String>::new(),
+            errors("query($id: ID = 1000) {human(id: $id){name}}", json!({}))
+        );
+        assert_eq!(
+            vec![
+                r#"Variable "$episodes" of type "[Episode]" used in position expecting type "Episode"."#
+            ],
+            errors(
+                "query($episodes: [Episode]) {hero(episode: $episodes){name}}",
+                json!({"episodes": ["JEDI"]})
+            )
+        );
+        assert_eq!(
+            Vec::<String>::new(),
+            errors(
+                "query($episode: Episode!) {hero(episode: $episode){name}}",
+                json!({"episode": "JEDI"})
+            )
+        );
+    }
+
+    #[test]
+    fn test_validate_type_conditions() {
+        crate::initialize_test_logging();
+        assert_eq!(
+            vec![
+                r#"Fragment cannot be spread here as objects of type "Human" can never be of type "Droid"."#
+            ],
+            errors("{human(id: 1){...F}} fragment F on Droid {name}", json!({}))
+        );
+        assert_eq!(
+            vec![r#"Unknown type "Wookiee"."#],
+            errors("{hero{... on Wookiee {name}}}", json!({}))
+        );
+        assert_eq!(
+            vec![r#"Fragment cannot condition on non composite type "Episode"."#],
+            errors("{hero{... on Episode {name}}}", json!({}))
+        );
+    }
+}
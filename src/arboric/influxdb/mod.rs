@@ -1,58 +1,518 @@
 //! The InfluxDB backend interface and configuration
+//!
+//! Points aren't written to InfluxDB while the request is being handled. Instead,
+//! they're queued and written in batches by a background writer thread, so that a
+//! slow or unreachable InfluxDB never holds up (or fails) a request.
 
-use influx_db_client::{Client, Point, Points, Precision, Value};
-use log::trace;
-use std::collections::HashMap;
+use super::metering::{milliseconds, RefusalEvent, Sink, UsageEvent};
+use influx_db_client::{Client, Error, Point, Points, Precision, Value};
+use log::{debug, trace, warn};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The read and write timeout, in seconds, of each write to InfluxDB
+const TIMEOUT_SECS: u64 = 5;
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub influx_db_uri: String,
     pub database: String,
     pub precision: Precision,
+    /// The most points that can be waiting to be written. Further points are dropped
+    pub queue_size: usize,
+    /// Points are written as soon as this many are waiting...
+    pub batch_size: usize,
+    /// ...or at least this often, whichever comes first
+    pub flush_interval: Duration,
+    /// How many times a failed write is retried before its points are dropped
+    pub max_retries: u32,
+    /// How long to wait before the first retry. Doubles after each retry
+    pub retry_backoff: Duration,
 }
 
 impl Config {
     pub fn new(uri: String, database: String) -> Config {
         Config {
             influx_db_uri: uri,
-            database: database,
+            database,
             precision: Precision::Milliseconds,
+            queue_size: 10_000,
+            batch_size: 500,
+            flush_interval: Duration::from_secs(1),
+            max_retries: 3,
+            retry_backoff: Duration::from_millis(500),
         }
     }
 }
 
-/// The arboric::influxdb::Backend does the actual work of writing
-/// a data point to InfluxDB
+/// The arboric::influxdb::Backend queues data points to be written to InfluxDB
+/// by its background writer. Clones share the same writer, which flushes any
+/// remaining points and stops once the last clone is dropped
 #[derive(Debug, Clone)]
 pub struct Backend {
     pub config: Config,
+    writer: Arc<Writer>,
 }
 
 impl Backend {
-    pub fn write_points(&self, map: &HashMap<String, usize>) {
-        let client = Client::new(
-            self.config.influx_db_uri.clone(),
-            self.config.database.clone(),
-        );
+    /// Constructs a new Backend, and starts its background writer
+    pub fn new(config: Config) -> Backend {
+        let writer = Writer::start(config.clone());
+        Backend {
+            config,
+            writer: Arc::new(writer),
+        }
+    }
+
+    /// Writes a point to the `queries` measurement for each top level field of an
+    /// operation, with the number of times it was selected, and one to the `requests`
+    /// measurement for the operation as a whole, with its latency. Both are tagged
+    /// with the operation's type and name (if any), the policy decision, the upstream
+    /// status (if it was forwarded) and the claims the listener logs. Claim tags are
+    /// prefixed with `claim_`, so a claim can't overwrite one of the other tags.
+    pub fn write_usage(&self, event: &UsageEvent) {
+        let timestamp = timestamp(self.config.precision);
+        let mut request = Point::new("requests");
+        for (claim, value) in event.claims.iter() {
+            request.add_tag(format!("claim_{}", claim), Value::String(value.clone()));
+        }
+        request
+            .add_tag(
+                "operation_type",
+                Value::String(event.operation_type.to_string()),
+            )
+            .add_tag(
+                "decision",
+                Value::String(event.decision.as_str().to_string()),
+            )
+            .add_field("fields", Value::Integer(event.total() as i64))
+            .add_field("latency_ms", Value::Float(milliseconds(event.latency)))
+            .add_timestamp(timestamp);
+        if let Some(ref operation_name) = event.operation_name {
+            request.add_tag("operation_name", Value::String(operation_name.clone()));
+        }
+        if let Some(status) = event.status {
+            request.add_tag("status", Value::String(status.to_string()));
+        }
+        if let Some(upstream_latency) = event.upstream_latency {
+            request.add_field(
+                "upstream_latency_ms",
+                Value::Float(milliseconds(upstream_latency)),
+            );
+        }
 
-        let mut points: Vec<Point> = Vec::new();
-        for (field, n) in map {
+        for (field, n) in event.counts.iter() {
             trace!("{}: {}", &field, &n);
-            let point = Point::new("queries")
+            let mut point = Point::new("queries");
+            point.tags = request.tags.clone();
+            let point = point
                 .add_tag("field", Value::String(field.clone()))
                 .add_field("n", Value::Integer((*n) as i64))
+                .add_timestamp(timestamp)
                 .to_owned();
-            points.push(point);
+            self.writer.enqueue(point);
         }
+        self.writer.enqueue(request);
+    }
 
-        // if Precision is None, the default is second
-        // Multiple write
-        let _ = client
-            .write_points(
-                Points::create_new(points),
-                Some(Precision::Milliseconds),
-                None,
-            )
+    /// Writes a point for a refused request, tagged with its HTTP status (or
+    /// `batch_entry_denied` for an entry of a batch) and error code, so that e.g. bad
+    /// credentials (401) can be told apart from policy denials (403)
+    pub fn write_refusal(&self, status: &str, code: &str) {
+        let point = Point::new("refusals")
+            .add_tag("status", Value::String(status.to_string()))
+            .add_tag("code", Value::String(code.to_string()))
+            .add_field("n", Value::Integer(1))
+            .add_timestamp(timestamp(self.config.precision))
+            .to_owned();
+        self.writer.enqueue(point);
+    }
+
+    /// The number of points that were dropped, either because the queue was full or
+    /// because they still couldn't be written after retrying
+    pub fn dropped(&self) -> usize {
+        self.writer.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Sink for Backend {
+    fn usage(&self, usage: &UsageEvent) {
+        self.write_usage(usage);
+    }
+
+    fn refusal(&self, refusal: &RefusalEvent) {
+        self.write_refusal(&refusal.status_label(), refusal.code);
+    }
+}
+
+/// The sending half of the queue, and the thread that writes what's sent on it
+#[derive(Debug)]
+struct Writer {
+    sender: Mutex<Option<SyncSender<Point>>>,
+    dropped: Arc<AtomicUsize>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Writer {
+    fn start(config: Config) -> Writer {
+        let (sender, receiver) = sync_channel(config.queue_size);
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let thread_dropped = dropped.clone();
+        let spawned = thread::Builder::new()
+            .name("influxdb-writer".into())
+            .spawn(move || run(config, receiver, thread_dropped));
+        match spawned {
+            Ok(thread) => Writer {
+                sender: Mutex::new(Some(sender)),
+                dropped,
+                thread: Some(thread),
+            },
+            Err(err) => {
+                warn!(
+                    "Unable to start InfluxDB writer, points will be dropped: {}",
+                    err
+                );
+                Writer {
+                    sender: Mutex::new(None),
+                    dropped,
+                    thread: None,
+                }
+            }
+        }
+    }
+
+    /// Queues the point without waiting, dropping it if the queue is full
+    fn enqueue(&self, point: Point) {
+        let sent = match self.sender.lock() {
+            Ok(sender) => sender
+                .as_ref()
+                .is_some_and(|sender| sender.try_send(point).is_ok()),
+            Err(_) => false,
+        };
+        if !sent {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for Writer {
+    /// Closes the queue, then waits for the writer to flush what's left in it
+    fn drop(&mut self) {
+        if let Ok(sender) = self.sender.get_mut() {
+            sender.take();
+        }
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("InfluxDB writer stopped unexpectedly");
+            }
+        }
+    }
+}
+
+/// The writer loop. Collects points from the queue until there's a full batch or the
+/// flush interval is up, then writes them. Returns once the queue is closed and
+/// everything in it has been written
+fn run(config: Config, receiver: Receiver<Point>, dropped: Arc<AtomicUsize>) {
+    let mut client = Client::new(config.influx_db_uri.clone(), config.database.clone());
+    client.set_read_timeout(TIMEOUT_SECS);
+    client.set_write_timeout(TIMEOUT_SECS);
+
+    let mut batch: Vec<Point> = Vec::with_capacity(config.batch_size);
+    let mut reported = 0;
+    let mut deadline = Instant::now() + config.flush_interval;
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let closed = match receiver.recv_timeout(timeout) {
+            Ok(point) => {
+                batch.push(point);
+                if batch.len() < config.batch_size {
+                    continue;
+                }
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+
+        if let Err(err) = flush(&client, &config, &batch) {
+            warn!(
+                "Unable to write {} points to InfluxDB, dropping them: {}",
+                batch.len(),
+                err
+            );
+            dropped.fetch_add(batch.len(), Ordering::Relaxed);
+        }
+        batch.clear();
+
+        let total = dropped.load(Ordering::Relaxed);
+        if total > reported {
+            warn!(
+                "Dropped {} InfluxDB points ({} in total)",
+                total - reported,
+                total
+            );
+            reported = total;
+        }
+
+        if closed {
+            debug!("InfluxDB writer stopped");
+            return;
+        }
+        deadline = Instant::now() + config.flush_interval;
+    }
+}
+
+/// Writes the batch of points, retrying with exponential backoff if InfluxDB can't
+/// be reached. Errors that won't go away by retrying, e.g. a missing database, are
+/// returned straight away
+fn flush(client: &Client, config: &Config, batch: &[Point]) -> Result<(), Error> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+    let mut backoff = config.retry_backoff;
+    let mut retries = 0;
+    loop {
+        let points = Points::create_new(batch.to_vec());
+        match client.write_points(points, Some(config.precision), None) {
+            Ok(()) => {
+                trace!("Wrote {} points to InfluxDB", batch.len());
+                return Ok(());
+            }
+            Err(err @ Error::Communication(_)) | Err(err @ Error::Unknow(_))
+                if retries < config.max_retries =>
+            {
+                debug!(
+                    "Unable to write to InfluxDB ({}), retrying in {:?}",
+                    err, backoff
+                );
+                thread::sleep(backoff);
+                backoff *= 2;
+                retries += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// The current time since the epoch, in the given precision
+fn timestamp(precision: Precision) -> i64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let timestamp = match precision {
+        Precision::Nanoseconds => now.as_nanos(),
+        Precision::Microseconds => now.as_micros(),
+        Precision::Milliseconds => now.as_millis(),
+        Precision::Seconds => u128::from(now.as_secs()),
+        Precision::Minutes => u128::from(now.as_secs() / 60),
+        Precision::Hours => u128::from(now.as_secs() / 3600),
+    };
+    timestamp as i64
+}
+
+#[cfg(test)]
+mod tests {
+    // Import names from outer (for mod tests) scope.
+    use super::*;
+
+    use futures::{Future, Stream};
+    use hyper::service::service_fn;
+    use hyper::{Body, Request, Response, Server, StatusCode};
+
+    /// Starts a stand-in for InfluxDB, that accepts any write and keeps the line
+    /// protocol bodies it was sent
+    fn influx_db(runtime: &mut tokio::runtime::Runtime) -> (String, Arc<Mutex<Vec<String>>>) {
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let server_writes = writes.clone();
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(move || {
+            let writes = server_writes.clone();
+            service_fn(move |req: Request<Body>| {
+                assert!(req.uri().path().ends_with("/write"));
+                assert!(req.uri().query().unwrap().contains("db=arboric"));
+                let writes = writes.clone();
+                req.into_body().concat2().map(move |body| {
+                    let lines = String::from_utf8_lossy(&body).into_owned();
+                    writes.lock().unwrap().push(lines);
+                    let mut response = Response::new(Body::empty());
+                    *response.status_mut() = StatusCode::NO_CONTENT;
+                    response
+                })
+            })
+        });
+        let uri = format!("http://{}", server.local_addr());
+        runtime.spawn(server.map_err(|e| panic!("{}", e)));
+        (uri, writes)
+    }
+
+    /// Waits (up to a few seconds) for the stand-in to have received `n` writes
+    fn wait_for_writes(writes: &Arc<Mutex<Vec<String>>>, n: usize) -> Vec<String> {
+        for _ in 0..100 {
+            if writes.lock().unwrap().len() >= n {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        writes.lock().unwrap().clone()
+    }
+
+    fn usage(query: &str, fields: &[(&str, usize)]) -> UsageEvent {
+        let document = graphql_parser::parse_query(query).unwrap();
+        let counts = fields
+            .iter()
+            .map(|(field, n)| (field.to_string(), *n))
+            .collect();
+        UsageEvent::new(([127, 0, 0, 1], 4000).into(), &document, counts, None, &[])
+    }
+
+    #[test]
+    fn test_points_are_written_in_batches() {
+        crate::initialize_test_logging();
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let (uri, writes) = influx_db(&mut runtime);
+
+        let mut config = Config::new(uri, "arboric".into());
+        config.batch_size = 3;
+        config.flush_interval = Duration::from_secs(60);
+        let backend = Backend::new(config);
+
+        let mut event = usage(
+            "query Feed { posts { id } users { id } posts { title } }",
+            &[("posts", 2), ("users", 1)],
+        );
+        event.claims = vec![
+            ("tenant".into(), "acme".into()),
+            ("status".into(), "active".into()),
+        ];
+        event.status = Some(200);
+        event.latency = Duration::from_millis(7);
+        event.upstream_latency = Some(Duration::from_millis(5));
+        backend.write_usage(&event);
+        let written = wait_for_writes(&writes, 1);
+        assert_eq!(1, written.len());
+        let lines: Vec<&str> = written[0].lines().collect();
+        assert_eq!(3, lines.len());
+        assert!(lines.iter().any(|line| line.starts_with("queries,")
+            && line.contains("field=posts")
+            && line.contains("n=2")));
+        assert!(lines.iter().all(|line| {
+            line.contains("operation_type=query")
+                && line.contains("operation_name=Feed")
+                && line.contains("decision=allowed")
+                && line.contains("status=200")
+                && line.contains("claim_tenant=acme")
+                && line.contains("claim_status=active")
+        }));
+        let request = lines
+            .iter()
+            .find(|line| line.starts_with("requests,"))
             .unwrap();
+        assert!(request.contains("fields=3"));
+        assert!(request.contains("latency_ms="));
+        assert!(request.contains("upstream_latency_ms="));
+
+        // Less than a batch is flushed when the backend is dropped
+        backend.write_refusal("403", "FORBIDDEN");
+        drop(backend);
+        let written = wait_for_writes(&writes, 2);
+        assert_eq!(2, written.len());
+        assert!(written[1].starts_with("refusals,"));
+        assert!(written[1].contains("code=FORBIDDEN"));
+    }
+
+    #[test]
+    fn test_points_are_written_every_flush_interval() {
+        crate::initialize_test_logging();
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let (uri, writes) = influx_db(&mut runtime);
+
+        let mut config = Config::new(uri, "arboric".into());
+        config.flush_interval = Duration::from_millis(50);
+        let backend = Backend::new(config);
+
+        backend.write_usage(&usage(
+            "mutation { createPost { id } }",
+            &[("createPost", 1)],
+        ));
+        let written = wait_for_writes(&writes, 1);
+        assert_eq!(1, written.len());
+        assert_eq!(2, written[0].lines().count());
+        assert!(written[0].contains("operation_type=mutation"));
+        assert!(!written[0].contains("status="));
+        assert_eq!(0, backend.dropped());
+    }
+
+    #[test]
+    fn test_points_are_dropped_when_the_queue_is_full() {
+        crate::initialize_test_logging();
+        // Nothing listens on port 1, so every write fails
+        let mut config = Config::new("http://127.0.0.1:1".into(), "arboric".into());
+        config.queue_size = 1;
+        config.batch_size = 1;
+        config.max_retries = 1;
+        config.retry_backoff = Duration::from_millis(10);
+        let backend = Backend::new(config);
+
+        for _ in 0..10 {
+            backend.write_refusal("401", "UNAUTHORIZED");
+        }
+        // At most one point is queued, and one more being written
+        assert!(backend.dropped() >= 8);
+
+        // The rest are dropped once they can't be written
+        let dropped = backend.writer.dropped.clone();
+        drop(backend);
+        assert_eq!(10, dropped.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_points_are_flushed_when_listeners_shut_down() {
+        use crate::arboric::proxy_service::test_support::anonymous_listener_config;
+        use std::io::{Read, Write};
+        use std::net::{Ipv4Addr, TcpListener, TcpStream};
+
+        crate::initialize_test_logging();
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let (uri, writes) = influx_db(&mut runtime);
+
+        // Nothing would be written until the listener stops
+        let mut config = Config::new(uri, "arboric".into());
+        config.flush_interval = Duration::from_secs(60);
+        let api_uri = "http://localhost:3001/graphql".parse().unwrap();
+        let mut listener_config = anonymous_listener_config(&api_uri);
+        let port = {
+            let socket = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            socket.local_addr().unwrap().port()
+        };
+        listener_config.listener_address.set_port(port);
+        listener_config.sinks.push(Arc::new(Backend::new(config)));
+        let server = crate::Listener::new(listener_config).bind().unwrap();
+
+        let (shutdown, shutdown_rx) = futures::sync::oneshot::channel::<()>();
+        let serving =
+            thread::spawn(move || crate::serve_until(vec![server], shutdown_rx.map_err(|_| ())));
+
+        let body = r#"{"query":"{secrets{value}}"}"#;
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        write!(
+            stream,
+            "POST /graphql HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+        assert!(writes.lock().unwrap().is_empty());
+
+        shutdown.send(()).unwrap();
+        serving.join().unwrap().unwrap();
+        let written = writes.lock().unwrap().clone();
+        assert_eq!(1, written.len());
+        assert!(written[0].contains("refusals,"), "{}", written[0]);
+        assert!(written[0].contains("decision=denied"), "{}", written[0]);
     }
 }
@@ -0,0 +1,424 @@
+//! Reads [jwt::Key](arboric::jwt::Key)s from a JSON Web Key Set (RFC 7517), either
+//! from a file or over HTTP(S)
+
+use super::{Algorithm, Key};
+use crate::arboric::tls::HttpsClient;
+use crate::config::JwksSource;
+use crate::ArboricError;
+use futures::{Future, Stream};
+use log::{trace, warn};
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Public};
+use openssl::rsa::Rsa;
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::timer::Timeout;
+
+/// The DER encoded SubjectPublicKeyInfo prefix of an Ed25519 public key,
+/// which is followed by the 32 byte key itself
+const ED25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+/// How long fetching a JWKS can take, unless configured otherwise
+pub const DEFAULT_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    alg: Option<String>,
+    #[serde(rename = "use")]
+    key_use: Option<String>,
+    crv: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+/// Loads the keys from the given [JwksSource](arboric::config::JwksSource). A JWKS URI is
+/// fetched synchronously, so this should be called before the listener starts.
+pub fn load(jwks_source: &JwksSource) -> crate::Result<Vec<Key>> {
+    match jwks_source {
+        JwksSource::FromFile { filename } => parse(&std::fs::read(filename)?),
+        JwksSource::FromUri { uri, timeout, .. } => {
+            let client = crate::arboric::tls::upstream_client(None)?;
+            let mut runtime = tokio::runtime::current_thread::Runtime::new()?;
+            runtime.block_on(fetch(&client, uri, *timeout))
+        }
+    }
+}
+
+/// Fetches and parses the JWKS at the given URI, failing if that takes longer than
+/// `timeout`. Needs a Tokio timer, e.g. a runtime's.
+pub fn fetch(
+    client: &HttpsClient,
+    uri: &str,
+    timeout: Duration,
+) -> Box<dyn Future<Item = Vec<Key>, Error = ArboricError> + Send> {
+    let parsed_uri = match uri.parse::<hyper::Uri>() {
+        Ok(parsed_uri) => parsed_uri,
+        Err(err) => {
+            return Box::new(futures::future::err(ArboricError::general(format!(
+                r#"Invalid JWKS uri "{}": {}"#,
+                uri, err
+            ))))
+        }
+    };
+    let uri = uri.to_string();
+    let timeout_uri = uri.clone();
+    let fetching = client
+        .get(parsed_uri)
+        .from_err()
+        .and_then(move |res| {
+            trace!("GET {} => {}", &uri, res.status());
+            if res.status().is_success() {
+                Ok(res)
+            } else {
+                Err(ArboricError::general(format!(
+                    "GET {} => {}",
+                    &uri,
+                    res.status()
+                )))
+            }
+        })
+        .and_then(|res| res.into_body().concat2().from_err())
+        .and_then(|chunk| parse(&chunk));
+    Box::new(Timeout::new(fetching, timeout).map_err(move |err| {
+        if err.is_elapsed() {
+            ArboricError::general(format!(
+                "GET {} timed out after {:?}",
+                &timeout_uri, timeout
+            ))
+        } else if err.is_inner() {
+            err.into_inner().unwrap()
+        } else {
+            ArboricError::general(format!("GET {}: timer error: {}", &timeout_uri, err))
+        }
+    }))
+}
+
+/// Parses a JWKS document. Keys that aren't signing keys, or that we don't support,
+/// are skipped.
+pub fn parse(json: &[u8]) -> crate::Result<Vec<Key>> {
+    let jwk_set: JwkSet = serde_json::from_slice(json)?;
+    let mut keys = Vec::new();
+    for jwk in jwk_set.keys.iter() {
+        if jwk.key_use.as_ref().is_some_and(|key_use| key_use != "sig") {
+            trace!("Skipping {:?}", jwk);
+            continue;
+        }
+        match to_key(jwk) {
+            Ok(key) => keys.push(key),
+            Err(err) => warn!("Skipping JWK {:?}: {}", &jwk.kid, err),
+        }
+    }
+    Ok(keys)
+}
+
+fn to_key(jwk: &Jwk) -> crate::Result<Key> {
+    let algorithm = match jwk.alg {
+        Some(ref alg) => alg.parse::<Algorithm>()?,
+        None => default_algorithm(jwk)?,
+    };
+    if algorithm.is_hmac() {
+        return Err(ArboricError::general(format!(
+            "{} keys aren't supported in a JWKS",
+            algorithm
+        )));
+    }
+    let pkey = match jwk.kty.as_str() {
+        "RSA" => rsa_public_key(jwk)?,
+        "EC" => ec_public_key(jwk)?,
+        "OKP" => ed25519_public_key(jwk)?,
+        kty => {
+            return Err(ArboricError::general(format!(
+                r#"Unsupported kty "{}""#,
+                kty
+            )))
+        }
+    };
+    Ok(Key::public_key(jwk.kid.clone(), algorithm, pkey))
+}
+
+fn default_algorithm(jwk: &Jwk) -> crate::Result<Algorithm> {
+    match (jwk.kty.as_str(), jwk.crv.as_deref()) {
+        ("RSA", _) => Ok(Algorithm::RS256),
+        ("EC", Some("P-256")) => Ok(Algorithm::ES256),
+        ("EC", Some("P-384")) => Ok(Algorithm::ES384),
+        ("OKP", Some("Ed25519")) => Ok(Algorithm::EdDSA),
+        (kty, crv) => Err(ArboricError::general(format!(
+            "No alg, and unable to infer one for kty {:?}, crv {:?}",
+            kty, crv
+        ))),
+    }
+}
+
+fn component(jwk: &Jwk, name: &str, value: &Option<String>) -> crate::Result<Vec<u8>> {
+    match value {
+        Some(s) => Ok(base64::decode_config(s, base64::URL_SAFE_NO_PAD)?),
+        None => Err(ArboricError::general(format!(
+            r#"{} key has no "{}""#,
+            jwk.kty, name
+        ))),
+    }
+}
+
+fn big_num(jwk: &Jwk, name: &str, value: &Option<String>) -> crate::Result<BigNum> {
+    Ok(BigNum::from_slice(&component(jwk, name, value)?)?)
+}
+
+fn rsa_public_key(jwk: &Jwk) -> crate::Result<PKey<Public>> {
+    let rsa = Rsa::from_public_components(big_num(jwk, "n", &jwk.n)?, big_num(jwk, "e", &jwk.e)?)?;
+    Ok(PKey::from_rsa(rsa)?)
+}
+
+fn ec_public_key(jwk: &Jwk) -> crate::Result<PKey<Public>> {
+    let nid = match jwk.crv.as_deref() {
+        Some("P-256") => Nid::X9_62_PRIME256V1,
+        Some("P-384") => Nid::SECP384R1,
+        crv => return Err(ArboricError::general(format!("Unsupported crv {:?}", crv))),
+    };
+    let group = EcGroup::from_curve_name(nid)?;
+    let x = big_num(jwk, "x", &jwk.x)?;
+    let y = big_num(jwk, "y", &jwk.y)?;
+    let ec_key = EcKey::from_public_key_affine_coordinates(&group, &x, &y)?;
+    Ok(PKey::from_ec_key(ec_key)?)
+}
+
+fn ed25519_public_key(jwk: &Jwk) -> crate::Result<PKey<Public>> {
+    if jwk.crv.as_deref() != Some("Ed25519") {
+        return Err(ArboricError::general(format!(
+            "Unsupported crv {:?}",
+            jwk.crv
+        )));
+    }
+    let x = component(jwk, "x", &jwk.x)?;
+    if x.len() != 32 {
+        return Err(ArboricError::general("Ed25519 key must be 32 bytes"));
+    }
+    let mut der = ED25519_SPKI_PREFIX.to_vec();
+    der.extend(x);
+    Ok(PKey::public_key_from_der(&der)?)
+}
+
+#[cfg(test)]
+mod tests {
+    // Import names from outer (for mod tests) scope.
+    use super::*;
+
+    use crate::arboric::jwt::test_support::sign;
+    use crate::arboric::jwt::KeySet;
+    use openssl::bn::BigNumContext;
+    use openssl::pkey::Private;
+    use serde_json::json;
+
+    fn encode(bytes: &[u8]) -> String {
+        base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+    }
+
+    fn rsa_jwk(kid: &str, key: &PKey<Private>) -> serde_json::Value {
+        let rsa = key.rsa().unwrap();
+        json!({
+            "kty": "RSA",
+            "kid": kid,
+            "use": "sig",
+            "n": encode(&rsa.n().to_vec()),
+            "e": encode(&rsa.e().to_vec()),
+        })
+    }
+
+    fn ec_jwk(kid: &str, key: &PKey<Private>) -> serde_json::Value {
+        let ec_key = key.ec_key().unwrap();
+        let mut x = BigNum::new().unwrap();
+        let mut y = BigNum::new().unwrap();
+        let mut ctx = BigNumContext::new().unwrap();
+        ec_key
+            .public_key()
+            .affine_coordinates_gfp(ec_key.group(), &mut x, &mut y, &mut ctx)
+            .unwrap();
+        json!({
+            "kty": "EC",
+            "kid": kid,
+            "crv": "P-256",
+            "x": encode(&x.to_vec()),
+            "y": encode(&y.to_vec()),
+        })
+    }
+
+    fn ed25519_jwk(kid: &str, key: &PKey<Private>) -> serde_json::Value {
+        let der = key.public_key_to_der().unwrap();
+        json!({
+            "kty": "OKP",
+            "kid": kid,
+            "crv": "Ed25519",
+            "x": encode(&der[ED25519_SPKI_PREFIX.len()..]),
+        })
+    }
+
+    #[test]
+    fn test_parse_jwks() {
+        crate::initialize_test_logging();
+        let rsa_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let ec_key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let ed25519_key = PKey::generate_ed25519().unwrap();
+        let mut encryption_key = rsa_jwk("enc", &rsa_key);
+        encryption_key["use"] = json!("enc");
+
+        let jwks = json!({
+            "keys": [
+                rsa_jwk("rsa", &rsa_key),
+                ec_jwk("ec", &ec_key),
+                ed25519_jwk("ed", &ed25519_key),
+                encryption_key,
+                { "kty": "oct", "kid": "hmac", "k": "c2VjcmV0" },
+            ]
+        });
+        let keys = parse(jwks.to_string().as_bytes()).unwrap();
+        assert_eq!(3, keys.len());
+        assert_eq!(Algorithm::RS256, keys[0].algorithm);
+        assert_eq!(Algorithm::ES256, keys[1].algorithm);
+        assert_eq!(Algorithm::EdDSA, keys[2].algorithm);
+
+        let key_set = KeySet::new(keys);
+        for (alg, kid, key) in [
+            ("RS256", "rsa", &rsa_key),
+            ("ES256", "ec", &ec_key),
+            ("EdDSA", "ed", &ed25519_key),
+        ]
+        .iter()
+        {
+            let token = sign(alg, Some(kid), key, &json!({ "sub": kid }));
+            let claims = key_set.verify(&token).unwrap();
+            assert_eq!(kid, claims.get("sub").unwrap());
+        }
+    }
+
+    #[test]
+    fn test_load_jwks_from_file() {
+        crate::initialize_test_logging();
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let jwks = json!({ "keys": [rsa_jwk("1", &key)] });
+        let path =
+            crate::arboric::tls::test_support::write_temp_file("jwks", jwks.to_string().as_bytes());
+        let keys = load(&JwksSource::FromFile {
+            filename: path.to_str().unwrap().into(),
+        })
+        .unwrap();
+        assert_eq!(1, keys.len());
+        assert_eq!(Some("1".to_string()), keys[0].kid);
+    }
+
+    #[test]
+    fn test_fetch_jwks() {
+        use hyper::service::service_fn_ok;
+        use hyper::{Body, Response, Server};
+
+        crate::initialize_test_logging();
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let jwks = json!({ "keys": [rsa_jwk("1", &key)] }).to_string();
+
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(move || {
+            let jwks = jwks.clone();
+            service_fn_ok(move |_req| Response::new(Body::from(jwks.clone())))
+        });
+        let uri = format!("http://{}/.well-known/jwks.json", server.local_addr());
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.spawn(server.map_err(|e| panic!("{}", e)));
+        let client = crate::arboric::tls::upstream_client(None).unwrap();
+        let keys = runtime
+            .block_on(fetch(&client, &uri, DEFAULT_FETCH_TIMEOUT))
+            .unwrap();
+        assert_eq!(1, keys.len());
+
+        let token = sign("RS256", Some("1"), &key, &json!({ "sub": "1" }));
+        assert!(KeySet::new(keys).verify(&token).is_ok());
+    }
+
+    #[test]
+    fn test_fetch_jwks_times_out() {
+        crate::initialize_test_logging();
+        // Connections are accepted (by the OS), but never answered
+        let socket = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let uri = format!(
+            "http://{}/.well-known/jwks.json",
+            socket.local_addr().unwrap()
+        );
+
+        let started = std::time::Instant::now();
+        let result = load(&JwksSource::FromUri {
+            uri: uri.clone(),
+            refresh_interval: Duration::from_secs(3600),
+            timeout: Duration::from_millis(200),
+        });
+        match result {
+            Err(ArboricError::GeneralError { message }) => {
+                assert_eq!(format!("GET {} timed out after 200ms", uri), message)
+            }
+            result => panic!("Expected a timeout, got {:?}!", result),
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_refresh_keeps_keys_after_a_fetch_times_out() {
+        use crate::config::{JwtSigningKey, JwtSigningKeySource};
+        use hyper::service::service_fn;
+        use hyper::{Body, Response, Server};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        crate::initialize_test_logging();
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let jwks = json!({ "keys": [rsa_jwk("1", &key)] }).to_string();
+
+        // Answers the first request, then never answers again
+        let requests = Arc::new(AtomicUsize::new(0));
+        let server_requests = requests.clone();
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(move || {
+            let jwks = jwks.clone();
+            let requests = server_requests.clone();
+            service_fn(
+                move |_req| -> Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send> {
+                    if requests.fetch_add(1, Ordering::SeqCst) == 0 {
+                        Box::new(futures::future::ok(Response::new(Body::from(jwks.clone()))))
+                    } else {
+                        Box::new(futures::future::empty())
+                    }
+                },
+            )
+        });
+        let uri = format!("http://{}/.well-known/jwks.json", server.local_addr());
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.spawn(server.map_err(|e| panic!("{}", e)));
+
+        let key_set = KeySet::load(&[JwtSigningKey::new(
+            None,
+            JwtSigningKeySource::Jwks(JwksSource::FromUri {
+                uri,
+                refresh_interval: Duration::from_millis(100),
+                timeout: Duration::from_millis(100),
+            }),
+        )])
+        .unwrap();
+        for task in key_set.refresh_tasks() {
+            runtime.spawn(task);
+        }
+        std::thread::sleep(Duration::from_millis(1000));
+
+        // Refreshes carried on after the first hung, and the keys were kept
+        assert!(requests.load(Ordering::SeqCst) >= 3);
+        let token = sign("RS256", Some("1"), &key, &json!({ "sub": "1" }));
+        assert!(key_set.verify(&token).is_ok());
+    }
+}
@@ -0,0 +1,255 @@
+//! Validation of the registered JWT claims (RFC 7519, section 4.1)
+
+use super::TokenError;
+use crate::Claims;
+use log::trace;
+use serde_json::Value;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A jwt::Validation defines which tokens a listener accepts, beyond having a valid
+/// signature:
+///
+/// * `exp` and `nbf`, if present, are always checked, allowing for `leeway` clock skew
+/// * if `issuers` isn't empty, the `iss` claim must be one of them
+/// * if `audiences` isn't empty, the `aud` claim must contain at least one of them
+/// * if `max_age` is given, the token must have an `iat` claim no older than that
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Validation {
+    pub issuers: Vec<String>,
+    pub audiences: Vec<String>,
+    pub leeway: Duration,
+    pub max_age: Option<Duration>,
+}
+
+impl Validation {
+    /// Validates the claims of a token as of now
+    pub fn validate(&self, claims: &Claims) -> Result<(), TokenError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.validate_at(claims, now)
+    }
+
+    /// Validates the claims of a token as of the given time, in seconds since the epoch
+    pub fn validate_at(&self, claims: &Claims, now: u64) -> Result<(), TokenError> {
+        trace!("validate_at({:?}, {})", claims, now);
+        // Huge NumericDates are read as u64::MAX, so the sums below saturate
+        let leeway = self.leeway.as_secs();
+        if let Some(exp) = numeric_date(claims, "exp")? {
+            if now > exp.saturating_add(leeway) {
+                return Err(TokenError::Expired);
+            }
+        }
+        if let Some(nbf) = numeric_date(claims, "nbf")? {
+            if now.saturating_add(leeway) < nbf {
+                return Err(TokenError::NotYetValid);
+            }
+        }
+        let iat = numeric_date(claims, "iat")?;
+        if let Some(iat) = iat {
+            if now.saturating_add(leeway) < iat {
+                return Err(TokenError::NotYetValid);
+            }
+        }
+        if let Some(max_age) = self.max_age {
+            let too_old_after =
+                |iat: u64| iat.saturating_add(max_age.as_secs()).saturating_add(leeway);
+            match iat {
+                Some(iat) if now <= too_old_after(iat) => (),
+                Some(_) => return Err(TokenError::TooOld),
+                None => return Err(TokenError::MissingClaim("iat".into())),
+            }
+        }
+        if !self.issuers.is_empty() {
+            match claims.get("iss") {
+                Some(Value::String(iss)) if self.issuers.contains(iss) => (),
+                Some(Value::String(iss)) => return Err(TokenError::InvalidIssuer(iss.clone())),
+                _ => return Err(TokenError::MissingClaim("iss".into())),
+            }
+        }
+        if !self.audiences.is_empty() {
+            let accepted = |aud: &Value| match aud {
+                Value::String(aud) => self.audiences.contains(aud),
+                _ => false,
+            };
+            match claims.get("aud") {
+                // aud can be a single string, or an array of them
+                Some(Value::Array(auds)) if auds.iter().any(&accepted) => (),
+                Some(aud) if accepted(aud) => (),
+                Some(_) => return Err(TokenError::InvalidAudience),
+                None => return Err(TokenError::MissingClaim("aud".into())),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Gets the given 'NumericDate' claim as whole seconds since the epoch
+fn numeric_date(claims: &Claims, claim: &str) -> Result<Option<u64>, TokenError> {
+    match claims.get(claim) {
+        None => Ok(None),
+        Some(value) => match value.as_f64() {
+            Some(secs) if secs >= 0.0 => Ok(Some(secs as u64)),
+            _ => Err(TokenError::MalformedToken(format!(
+                r#""{}" must be a NumericDate, got {}"#,
+                claim, value
+            ))),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Import names from outer (for mod tests) scope.
+    use super::*;
+
+    use serde_json::json;
+
+    const NOW: u64 = 1_570_000_000;
+
+    fn claims(value: serde_json::Value) -> Claims {
+        match value {
+            Value::Object(map) => map,
+            _ => panic!("Not an object"),
+        }
+    }
+
+    #[test]
+    fn test_validate_exp_and_nbf() {
+        let validation = Validation::default();
+        assert_eq!(Ok(()), validation.validate_at(&claims(json!({})), NOW));
+        assert_eq!(
+            Ok(()),
+            validation.validate_at(&claims(json!({"exp": NOW + 1, "nbf": NOW})), NOW)
+        );
+        assert_eq!(
+            Err(TokenError::Expired),
+            validation.validate_at(&claims(json!({"exp": NOW - 1})), NOW)
+        );
+        assert_eq!(
+            Err(TokenError::NotYetValid),
+            validation.validate_at(&claims(json!({"nbf": NOW + 1})), NOW)
+        );
+        assert!(validation
+            .validate_at(&claims(json!({"exp": "tomorrow"})), NOW)
+            .is_err());
+    }
+
+    #[test]
+    fn test_validate_leeway() {
+        let validation = Validation {
+            leeway: Duration::from_secs(30),
+            ..Validation::default()
+        };
+        assert_eq!(
+            Ok(()),
+            validation.validate_at(&claims(json!({"exp": NOW - 30, "nbf": NOW + 30})), NOW)
+        );
+        assert_eq!(
+            Err(TokenError::Expired),
+            validation.validate_at(&claims(json!({"exp": NOW - 31})), NOW)
+        );
+    }
+
+    #[test]
+    fn test_validate_max_age() {
+        let validation = Validation {
+            max_age: Some(Duration::from_secs(3600)),
+            ..Validation::default()
+        };
+        assert_eq!(
+            Ok(()),
+            validation.validate_at(&claims(json!({"iat": NOW - 3600})), NOW)
+        );
+        assert_eq!(
+            Err(TokenError::TooOld),
+            validation.validate_at(&claims(json!({"iat": NOW - 3601})), NOW)
+        );
+        assert_eq!(
+            Err(TokenError::MissingClaim("iat".into())),
+            validation.validate_at(&claims(json!({})), NOW)
+        );
+    }
+
+    #[test]
+    fn test_validate_huge_numeric_dates() {
+        let validation = Validation {
+            leeway: Duration::from_secs(30),
+            max_age: Some(Duration::from_secs(3600)),
+            ..Validation::default()
+        };
+        assert_eq!(
+            Ok(()),
+            validation.validate_at(&claims(json!({"exp": 1e300, "iat": NOW})), NOW)
+        );
+        assert_eq!(
+            Ok(()),
+            validation.validate_at(&claims(json!({"exp": u64::MAX, "iat": NOW})), NOW)
+        );
+        assert_eq!(
+            Err(TokenError::NotYetValid),
+            validation.validate_at(&claims(json!({"iat": u64::MAX})), NOW)
+        );
+        assert_eq!(
+            Err(TokenError::NotYetValid),
+            validation.validate_at(&claims(json!({"nbf": 1e300, "iat": NOW})), NOW)
+        );
+        assert_eq!(
+            Err(TokenError::Expired),
+            validation.validate_at(&claims(json!({"exp": NOW, "iat": NOW})), u64::MAX)
+        );
+        let forever = Validation {
+            leeway: Duration::from_secs(u64::MAX),
+            max_age: Some(Duration::from_secs(u64::MAX)),
+            ..Validation::default()
+        };
+        assert_eq!(
+            Ok(()),
+            forever.validate_at(&claims(json!({"exp": NOW, "iat": NOW})), u64::MAX)
+        );
+    }
+
+    #[test]
+    fn test_validate_issuer_and_audience() {
+        let validation = Validation {
+            issuers: vec!["https://login.example.com/".into()],
+            audiences: vec!["arboric".into()],
+            ..Validation::default()
+        };
+        assert_eq!(
+            Ok(()),
+            validation.validate_at(
+                &claims(json!({"iss": "https://login.example.com/", "aud": "arboric"})),
+                NOW
+            )
+        );
+        assert_eq!(
+            Ok(()),
+            validation.validate_at(
+                &claims(json!({"iss": "https://login.example.com/", "aud": ["other", "arboric"]})),
+                NOW
+            )
+        );
+        assert_eq!(
+            Err(TokenError::InvalidIssuer(
+                "https://evil.example.com/".into()
+            )),
+            validation.validate_at(
+                &claims(json!({"iss": "https://evil.example.com/", "aud": "arboric"})),
+                NOW
+            )
+        );
+        assert_eq!(
+            Err(TokenError::InvalidAudience),
+            validation.validate_at(
+                &claims(json!({"iss": "https://login.example.com/", "aud": "other"})),
+                NOW
+            )
+        );
+        assert_eq!(
+            Err(TokenError::MissingClaim("aud".into())),
+            validation.validate_at(&claims(json!({"iss": "https://login.example.com/"})), NOW)
+        );
+    }
+}
@@ -1,14 +1,24 @@
 //! The main proxy that implements hyper::NewService
 //!
-use crate::config::ListenerConfig;
+use super::tls;
+use crate::config::{ClientAuthConfig, ListenerConfig, SchemaSource, TlsConfig};
+use crate::ArboricError;
 use futures::future;
-use futures::Future;
+use futures::{Future, Stream};
 use http::Uri;
+use hyper::server::conn::Http;
 use hyper::service::NewService;
 use hyper::{Body, Server};
-use log::{info, trace};
+use log::{debug, error, info, trace, warn};
+use openssl::ssl::SslAcceptor;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_openssl::SslAcceptorExt;
+
+/// The future returned by [Listener::bind](arboric::Listener::bind), which
+/// serves requests until the server fails
+pub type Serve = Box<dyn Future<Item = (), Error = ()> + Send>;
 
 /// The main Proxy/Listener
 #[derive(Debug)]
@@ -20,51 +30,257 @@ pub struct Listener {
 pub struct ListenerContext {
     pub listener_address: SocketAddr,
     pub listener_path: Option<String>,
+    pub tls: Option<TlsConfig>,
     pub api_uri: Uri,
+    pub client: tls::HttpsClient,
+    pub upstream_connector: tls::UpstreamConnector,
     pub pdp: crate::abac::PDP,
-    pub influx_db_backend: Option<super::influxdb::Backend>,
-    pub secret_key_bytes: Option<Vec<u8>>,
+    pub schema: Option<Arc<crate::graphql::Schema>>,
+    pub sinks: Vec<Arc<dyn super::metering::Sink>>,
+    pub log_claims: Vec<String>,
+    pub metrics: Option<Arc<super::metrics::Metrics>>,
+    pub jwt_keys: Option<crate::jwt::KeySet>,
+    pub jwt_validation: crate::jwt::Validation,
+    pub anonymous: bool,
+    pub error_verbosity: crate::config::ErrorVerbosity,
+    pub batch: crate::config::BatchConfig,
+}
+
+impl ListenerContext {
+    /// Whether callers must authenticate, either with a JWT or a client certificate
+    pub fn authenticates(&self) -> bool {
+        self.jwt_keys.is_some() || self.client_auth().is_some()
+    }
+
+    /// The `listener` label of this listener's metrics: its address
+    pub fn metrics_label(&self) -> String {
+        self.listener_address.to_string()
+    }
+
+    fn client_auth(&self) -> Option<&ClientAuthConfig> {
+        self.tls
+            .as_ref()
+            .and_then(|tls_config| tls_config.client_auth.as_ref())
+    }
 }
 
 impl Listener {
     /// Constructs a new Listener with the given backend API URI
+    ///
+    /// # Panics
+    ///
+    /// If the JWT signing key or upstream TLS configuration can't be loaded. See
+    /// [Listener::try_new](arboric::Listener::try_new)
     pub fn new(listener_config: ListenerConfig) -> Self {
-        let secret_key_bytes;
-        if let Some(key_source) = &listener_config.jwt_signing_key_source {
-            match key_source.get_secret_key_bytes() {
-                Ok(bytes) => {
-                    trace!("secret_key_bytes => {:?}", bytes);
-                    secret_key_bytes = Some(bytes);
-                }
-                Err(err) => panic!("Enable to get secret key bytes: {}!", err),
-            }
+        match Listener::try_new(listener_config) {
+            Ok(listener) => listener,
+            Err(err) => panic!("Unable to configure listener: {}!", err),
+        }
+    }
+
+    /// Constructs a new Listener with the given backend API URI, returning an error
+    /// if the JWT signing key, schema or upstream TLS configuration can't be loaded
+    pub fn try_new(listener_config: ListenerConfig) -> crate::Result<Self> {
+        let jwt_keys = if listener_config.jwt_signing_keys.is_empty() {
+            None
         } else {
-            secret_key_bytes = None;
+            let key_set = crate::jwt::KeySet::load(&listener_config.jwt_signing_keys)?;
+            trace!("jwt_keys => {:?}", key_set);
+            Some(key_set)
+        };
+        let schema = match listener_config.schema {
+            Some(SchemaSource::FromFile { ref filename }) => {
+                Some(Arc::new(crate::graphql::Schema::from_file(filename)?))
+            }
+            None => None,
+        };
+        if schema.is_none() && listener_config.pdp.requires_schema() {
+            return Err(ArboricError::general(
+                "Policies with type patterns require a schema",
+            ));
+        }
+        let client = tls::upstream_client(listener_config.upstream_tls.as_ref())?;
+        let upstream_connector =
+            tls::UpstreamConnector::new(listener_config.upstream_tls.as_ref())?;
+        let mut sinks = listener_config.sinks;
+        if let Some(ref metrics) = listener_config.metrics {
+            sinks.push(metrics.clone());
         }
         let context = ListenerContext {
             listener_address: listener_config.listener_address,
             listener_path: listener_config.listener_path,
+            tls: listener_config.tls,
             api_uri: listener_config.api_uri,
+            client,
+            upstream_connector,
             pdp: listener_config.pdp,
-            influx_db_backend: listener_config.influx_db_backend,
-            secret_key_bytes,
+            schema,
+            sinks,
+            log_claims: listener_config.log_claims,
+            metrics: listener_config.metrics,
+            jwt_keys,
+            jwt_validation: listener_config.jwt_validation,
+            anonymous: listener_config.anonymous,
+            error_verbosity: listener_config.error_verbosity,
+            batch: listener_config.batch,
         };
-        Listener {
+        Ok(Listener {
             context: Arc::new(context),
+        })
+    }
+
+    /// The address this Listener will bind to
+    pub fn listener_address(&self) -> SocketAddr {
+        self.context.listener_address
+    }
+
+    #[cfg(test)]
+    pub(crate) fn context(&self) -> Arc<ListenerContext> {
+        self.context.clone()
+    }
+
+    /// Binds this Listener to its address and returns the future that serves
+    /// requests on it. The future needs to be spawned on (or run by) a Tokio
+    /// runtime, which allows several Listeners to share a single runtime.
+    pub fn bind(self) -> crate::Result<Serve> {
+        let refresh_tasks = match self.context.jwt_keys {
+            Some(ref key_set) => key_set.refresh_tasks(),
+            None => Vec::new(),
+        };
+        let server = match self.context.tls {
+            Some(ref tls_config) => {
+                let acceptor = tls::acceptor(tls_config)?;
+                self.bind_tls(acceptor)?
+            }
+            None => self.bind_http()?,
+        };
+        if refresh_tasks.is_empty() {
+            return Ok(server);
         }
+        // Keep the JWT keys up to date on the same runtime the server runs on
+        Ok(Box::new(future::lazy(move || {
+            for refresh_task in refresh_tasks {
+                hyper::rt::spawn(refresh_task);
+            }
+            server
+        })))
     }
 
-    pub fn run(self) -> ! {
-        let bound = Server::bind(&self.context.listener_address);
-        info!("Proxy listening on {}", &self.context.listener_address);
+    fn bind_http(self) -> crate::Result<Serve> {
+        let listener_address = self.context.listener_address;
+        let bound =
+            Server::try_bind(&listener_address).map_err(|cause| ArboricError::HyperError {
+                message: format!("Unable to bind to {}: {}", &listener_address, cause),
+                cause,
+            })?;
+        info!("Proxy listening on {}", &listener_address);
         let server = bound
             .serve(self)
-            .map_err(|e| eprintln!("server error: {}", e));
+            .map_err(move |e| error!("Listener on {}: server error: {}", &listener_address, e));
+        Ok(Box::new(server))
+    }
+
+    /// Since hyper's `Server` only accepts plain TCP connections, we accept and
+    /// perform the TLS handshake ourselves, then hand each connection to hyper,
+    /// serving HTTP/2 if that's what the client negotiated via ALPN.
+    fn bind_tls(self, acceptor: SslAcceptor) -> crate::Result<Serve> {
+        let listener_address = self.context.listener_address;
+        let tcp_listener =
+            TcpListener::bind(&listener_address).map_err(|cause| ArboricError::IoError {
+                message: format!("Unable to bind to {}: {}", &listener_address, cause),
+                cause,
+            })?;
+        info!("Proxy listening on {} (TLS)", &listener_address);
+        let context = self.context;
+        let http = Http::new();
+        let server = tcp_listener
+            .incoming()
+            .then(move |result| {
+                match result {
+                    Ok(tcp_stream) => {
+                        let connection =
+                            serve_tls_connection(context.clone(), &acceptor, &http, tcp_stream);
+                        hyper::rt::spawn(connection);
+                    }
+                    Err(e) => warn!("Listener on {}: accept error: {}", &listener_address, e),
+                }
+                Ok(())
+            })
+            .for_each(|_| Ok(()));
+        Ok(Box::new(server))
+    }
+
+    /// Binds and runs this Listener on its own runtime
+    pub fn run(self) -> ! {
+        match self.bind() {
+            Ok(server) => {
+                // Run this server for... forever!
+                hyper::rt::run(server);
+                std::process::exit(0);
+            }
+            Err(err) => {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+}
 
-        // Run this server for... forever!
-        hyper::rt::run(server);
-        std::process::exit(0);
+/// Serves the given (bound) servers on a new runtime until `shutdown` completes. Then
+/// stops serving, dropping the servers and any connections they have open, so that
+/// their listeners, and the sinks they log to, are dropped as well. Sinks that queue
+/// what they log (such as InfluxDB) flush it as they're dropped, before this returns.
+pub fn serve_until<F>(servers: Vec<Serve>, shutdown: F) -> crate::Result<()>
+where
+    F: Future<Item = (), Error = ()> + Send + 'static,
+{
+    let mut runtime = tokio::runtime::Runtime::new().map_err(|cause| ArboricError::IoError {
+        message: format!("Unable to start the runtime: {}", cause),
+        cause,
+    })?;
+    for server in servers.into_iter() {
+        runtime.spawn(server);
     }
+    let _ = runtime.block_on(shutdown);
+    info!("Shutting down");
+    runtime
+        .shutdown_now()
+        .wait()
+        .map_err(|_| ArboricError::general("Unable to shut down the runtime"))
+}
+
+/// Performs the TLS handshake on an accepted connection, then serves it
+fn serve_tls_connection(
+    context: Arc<ListenerContext>,
+    acceptor: &SslAcceptor,
+    http: &Http,
+    tcp_stream: TcpStream,
+) -> impl Future<Item = (), Error = ()> + Send {
+    let peer_addr = tcp_stream.peer_addr().ok();
+    let mut http = http.clone();
+    acceptor
+        .accept_async(tcp_stream)
+        .map_err(move |e| debug!("TLS handshake with {:?} failed: {}", &peer_addr, e))
+        .and_then(move |tls_stream| {
+            let ssl = tls_stream.get_ref().ssl();
+            let http2 = ssl.selected_alpn_protocol() == Some(tls::ALPN_H2);
+            trace!("{:?} negotiated HTTP/2? {}", &peer_addr, http2);
+            let client_certificate_claims = context
+                .client_auth()
+                .and_then(|client_auth| tls::peer_certificate_claims(ssl, &client_auth.claims));
+            debug!(
+                "{:?} client certificate claims => {:?}",
+                &peer_addr, &client_certificate_claims
+            );
+            let service = super::ProxyService::with_client_certificate_claims(
+                context,
+                client_certificate_claims,
+            );
+            http.http2_only(http2);
+            http.serve_connection(tls_stream, service)
+                .with_upgrades()
+                .map_err(move |e| warn!("Connection from {:?}: {}", &peer_addr, e))
+        })
 }
 
 impl NewService for Listener {
@@ -81,3 +297,150 @@ impl NewService for Listener {
         Box::new(future::ok(super::ProxyService::new(self.context.clone())))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    // Import names from outer (for mod tests) scope.
+    use super::*;
+
+    use std::net::{IpAddr, Ipv4Addr, TcpListener};
+
+    fn listener_on(port: u16) -> Listener {
+        let api_uri = "http://localhost:3001/graphql".parse::<Uri>().unwrap();
+        let listener_config =
+            ListenerConfig::ip_addr_and_port(IpAddr::V4(Ipv4Addr::LOCALHOST), port, &api_uri);
+        Listener::new(listener_config)
+    }
+
+    /// Asks the OS for a port that's free at the time of calling
+    fn free_port() -> u16 {
+        let socket = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket.local_addr().unwrap().port()
+    }
+
+    #[test]
+    fn test_bind_multiple_listeners() {
+        crate::initialize_test_logging();
+        let first = listener_on(free_port());
+        let second = listener_on(free_port());
+        assert_ne!(first.listener_address(), second.listener_address());
+        assert!(first.bind().is_ok());
+        assert!(second.bind().is_ok());
+    }
+
+    /// Makes a `PUT` request over TLS to the listener on the given port and returns
+    /// the raw response. Since the request is neither a GET nor a POST, it's answered
+    /// with a 404 without ever needing the upstream API.
+    fn put_over_tls(
+        runtime: &mut tokio::runtime::Runtime,
+        port: u16,
+        connector: openssl::ssl::SslConnector,
+    ) -> Result<String, String> {
+        use tokio_openssl::SslConnectorExt;
+
+        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+        let (_, bytes) = runtime.block_on(
+            TcpStream::connect(&address)
+                .map_err(|e| e.to_string())
+                .and_then(move |tcp_stream| {
+                    connector
+                        .connect_async("localhost", tcp_stream)
+                        .map_err(|e| e.to_string())
+                })
+                .and_then(|tls_stream| {
+                    tokio::io::write_all(
+                        tls_stream,
+                        "PUT / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                    )
+                    .map_err(|e| e.to_string())
+                })
+                .and_then(|(tls_stream, _)| {
+                    tokio::io::read_to_end(tls_stream, Vec::new()).map_err(|e| e.to_string())
+                }),
+        )?;
+        Ok(String::from_utf8_lossy(&bytes).to_string())
+    }
+
+    fn tls_listener_on(port: u16, tls_config: TlsConfig) -> Listener {
+        let api_uri = "http://localhost:3001/graphql".parse::<Uri>().unwrap();
+        let mut listener_config =
+            ListenerConfig::ip_addr_and_port(IpAddr::V4(Ipv4Addr::LOCALHOST), port, &api_uri);
+        listener_config.tls = Some(tls_config);
+        Listener::new(listener_config)
+    }
+
+    #[test]
+    fn test_bind_tls() {
+        use openssl::ssl::{SslConnector, SslMethod};
+
+        crate::initialize_test_logging();
+        let (tls_config, certificate) =
+            tls::test_support::self_signed_tls_config("test_bind_tls", false);
+        let port = free_port();
+        let server = tls_listener_on(port, tls_config).bind().unwrap();
+
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.cert_store_mut().add_cert(certificate).unwrap();
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.spawn(server);
+        let response = put_over_tls(&mut runtime, port, connector.build()).unwrap();
+        assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+    }
+
+    #[test]
+    fn test_bind_tls_client_auth_required() {
+        use crate::config::ClientAuthConfig;
+        use openssl::ssl::{SslConnector, SslMethod};
+        use tls::test_support::*;
+
+        crate::initialize_test_logging();
+        let (mut tls_config, server_certificate) =
+            self_signed_tls_config("test_bind_tls_client_auth_required", false);
+        let client_key = private_key();
+        let client_certificate = self_signed_certificate("client", &client_key);
+        let client_ca_bundle = write_temp_file(
+            "test_bind_tls_client_auth_required-client",
+            &client_certificate.to_pem().unwrap(),
+        );
+        tls_config.client_auth = Some(ClientAuthConfig::new(client_ca_bundle.to_string_lossy()));
+        let port = free_port();
+        let server = tls_listener_on(port, tls_config).bind().unwrap();
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.spawn(server);
+
+        let mut anonymous = SslConnector::builder(SslMethod::tls()).unwrap();
+        anonymous
+            .cert_store_mut()
+            .add_cert(server_certificate.clone())
+            .unwrap();
+        if let Ok(response) = put_over_tls(&mut runtime, port, anonymous.build()) {
+            assert!(!response.starts_with("HTTP/1.1"), "{}", response)
+        }
+
+        let mut authenticated = SslConnector::builder(SslMethod::tls()).unwrap();
+        authenticated
+            .cert_store_mut()
+            .add_cert(server_certificate)
+            .unwrap();
+        authenticated.set_certificate(&client_certificate).unwrap();
+        authenticated.set_private_key(&client_key).unwrap();
+        let response = put_over_tls(&mut runtime, port, authenticated.build()).unwrap();
+        assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+    }
+
+    #[test]
+    fn test_bind_address_in_use() {
+        crate::initialize_test_logging();
+        let socket = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        match listener_on(port).bind() {
+            Err(ArboricError::HyperError { message, .. }) => {
+                assert!(message.starts_with("Unable to bind to"))
+            }
+            Err(err) => panic!("Expected ArboricError::HyperError, got {:?}!", err),
+            Ok(_) => panic!("Expected bind() to fail on {}!", port),
+        }
+    }
+}
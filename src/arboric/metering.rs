@@ -0,0 +1,364 @@
+//! Metering: what each GraphQL request used, who made it, and how it was handled.
+//! Each listener sends a [UsageEvent](arboric::metering::UsageEvent) for every request
+//! it handles, and a [RefusalEvent](arboric::metering::RefusalEvent) for every request
+//! it refuses, to each of its [Sink](arboric::metering::Sink)s.
+
+use crate::graphql;
+use crate::Claims;
+use graphql_parser::query::Document;
+use log::warn;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{LineWriter, Write};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A [Sink](arboric::metering::Sink) records usage and refusal events, e.g. by writing
+/// them to InfluxDB. A listener can have any number of sinks, which are called
+/// while requests are being handled, so they shouldn't block for long.
+pub trait Sink: fmt::Debug + Send + Sync {
+    /// Records a request that's been handled
+    fn usage(&self, usage: &UsageEvent);
+
+    /// Records a request that was refused. Does nothing by default
+    fn refusal(&self, _refusal: &RefusalEvent) {}
+}
+
+/// Whether the policies allowed a request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    Allowed,
+    Denied,
+}
+
+impl Decision {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Decision::Allowed => "allowed",
+            Decision::Denied => "denied",
+        }
+    }
+}
+
+/// A [UsageEvent](arboric::metering::UsageEvent) is written for each request with a
+/// valid GraphQL operation, once it's been handled. Requests that are refused before
+/// then, e.g. for a missing token, are only logged as refusals.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageEvent {
+    /// The address of the listener that handled the request
+    pub listener: SocketAddr,
+    /// `"query"`, `"mutation"` or `"subscription"`
+    pub operation_type: &'static str,
+    pub operation_name: Option<String>,
+    /// The number of times each top level field was selected
+    pub counts: HashMap<String, usize>,
+    /// The values of the claims the listener logs, that the caller has
+    pub claims: Vec<(String, String)>,
+    pub decision: Decision,
+    /// The status of the upstream API's response. `None` if the request wasn't
+    /// forwarded, or no response came back
+    pub status: Option<u16>,
+    /// From when the request arrived until the response was ready to send back
+    pub latency: Duration,
+    /// From when the request was forwarded until the upstream API responded
+    pub upstream_latency: Option<Duration>,
+}
+
+impl UsageEvent {
+    /// Constructs an allowed UsageEvent for the operation in the given document,
+    /// that hasn't been forwarded yet
+    pub fn new(
+        listener: SocketAddr,
+        document: &Document,
+        counts: HashMap<String, usize>,
+        claims: Option<&Claims>,
+        log_claims: &[String],
+    ) -> UsageEvent {
+        let operation = graphql::document_operation(document);
+        UsageEvent {
+            listener,
+            operation_type: operation.map_or("query", graphql::operation_type),
+            operation_name: operation
+                .and_then(graphql::operation_name)
+                .map(String::from),
+            counts,
+            claims: claims.map_or_else(Vec::new, |claims| claim_tags(claims, log_claims)),
+            decision: Decision::Allowed,
+            status: None,
+            latency: Duration::default(),
+            upstream_latency: None,
+        }
+    }
+
+    /// The total number of top level fields selected
+    pub fn total(&self) -> usize {
+        self.counts.values().sum()
+    }
+
+    /// The event as a JSON object, with its latencies in milliseconds
+    pub fn to_json(&self) -> Value {
+        let claims: serde_json::Map<String, Value> = self
+            .claims
+            .iter()
+            .map(|(claim, value)| (claim.clone(), Value::String(value.clone())))
+            .collect();
+        json!({
+            "event": "usage",
+            "listener": self.listener.to_string(),
+            "operation_type": self.operation_type,
+            "operation_name": self.operation_name,
+            "counts": self.counts,
+            "claims": claims,
+            "decision": self.decision.as_str(),
+            "status": self.status,
+            "latency_ms": milliseconds(self.latency),
+            "upstream_latency_ms": self.upstream_latency.map(milliseconds),
+        })
+    }
+}
+
+/// The status a refused batch entry is recorded with by metrics and InfluxDB, since
+/// the response to its batch wasn't refused
+pub static BATCH_ENTRY_DENIED: &str = "batch_entry_denied";
+
+/// A [RefusalEvent](arboric::metering::RefusalEvent) is written for each request a
+/// listener refuses, whether or not it had a valid GraphQL operation
+#[derive(Debug, Clone, PartialEq)]
+pub struct RefusalEvent {
+    /// The address of the listener that refused the request
+    pub listener: SocketAddr,
+    /// The HTTP status the request was refused with
+    pub status: u16,
+    /// The error code, e.g. `"UNAUTHENTICATED"`
+    pub code: &'static str,
+    /// `Denied` if the policies didn't allow the request, `None` if it was refused
+    /// before they were evaluated
+    pub decision: Option<Decision>,
+    /// Why the bearer token was rejected, e.g. `"expired"`, if that's why
+    pub token_error: Option<&'static str>,
+    /// Whether the request was an entry of a batch that was refused on its own (with
+    /// `on_denied: deny_entries`), in which case `status` is the status it would have
+    /// been refused with, not the batch's
+    pub batch_entry: bool,
+}
+
+impl RefusalEvent {
+    /// The status to record the refusal with: its HTTP status, or
+    /// `"batch_entry_denied"` for a batch entry
+    pub fn status_label(&self) -> String {
+        if self.batch_entry {
+            BATCH_ENTRY_DENIED.to_string()
+        } else {
+            self.status.to_string()
+        }
+    }
+
+    /// The event as a JSON object
+    pub fn to_json(&self) -> Value {
+        json!({
+            "event": "refusal",
+            "listener": self.listener.to_string(),
+            "status": self.status,
+            "code": self.code,
+            "decision": self.decision.map(Decision::as_str),
+            "token_error": self.token_error,
+            "batch_entry": self.batch_entry,
+        })
+    }
+}
+
+/// A [JsonLines](arboric::metering::JsonLines) sink writes each event as a line of
+/// JSON, with a `timestamp` in milliseconds since the epoch, to stdout or a file
+pub struct JsonLines {
+    name: String,
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl JsonLines {
+    /// A JsonLines sink that writes to stdout
+    pub fn stdout() -> JsonLines {
+        JsonLines {
+            name: "stdout".into(),
+            writer: Mutex::new(Box::new(std::io::stdout())),
+        }
+    }
+
+    /// A JsonLines sink that appends to the given file, creating it if need be
+    pub fn file(location: &str) -> crate::Result<JsonLines> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(location)
+            .map_err(|cause| crate::ArboricError::IoError {
+                message: format!("Unable to open {}: {}", location, cause),
+                cause,
+            })?;
+        Ok(JsonLines {
+            name: location.into(),
+            writer: Mutex::new(Box::new(LineWriter::new(file))),
+        })
+    }
+
+    fn write(&self, mut json: Value) {
+        json["timestamp"] = json!(SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64);
+        let written = match self.writer.lock() {
+            Ok(mut writer) => writeln!(writer, "{}", json),
+            Err(_) => return,
+        };
+        if let Err(err) = written {
+            warn!("Unable to write event to {}: {}", &self.name, err);
+        }
+    }
+}
+
+impl fmt::Debug for JsonLines {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "JsonLines({})", &self.name)
+    }
+}
+
+impl Sink for JsonLines {
+    fn usage(&self, usage: &UsageEvent) {
+        self.write(usage.to_json());
+    }
+
+    fn refusal(&self, refusal: &RefusalEvent) {
+        self.write(refusal.to_json());
+    }
+}
+
+/// The duration in (fractional) milliseconds
+pub(crate) fn milliseconds(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// The values of the given claims, as tags. Arrays (e.g. of roles) are joined with
+/// commas, and objects are left out.
+fn claim_tags(claims: &Claims, log_claims: &[String]) -> Vec<(String, String)> {
+    log_claims
+        .iter()
+        .filter_map(|key| {
+            claims
+                .get(key)
+                .and_then(claim_tag)
+                .map(|value| (key.clone(), value))
+        })
+        .collect()
+}
+
+fn claim_tag(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        Value::Array(values) => {
+            let values: Vec<String> = values.iter().filter_map(claim_tag).collect();
+            if values.is_empty() {
+                None
+            } else {
+                Some(values.join(","))
+            }
+        }
+        Value::Null | Value::Object(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Import names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_usage_event() {
+        crate::initialize_test_logging();
+        let document =
+            graphql_parser::parse_query("mutation AddPost { createPost { id } }").unwrap();
+        let mut counts = HashMap::new();
+        counts.insert("createPost".to_string(), 1);
+        let claims = json!({
+            "sub": "1234567890",
+            "tenant": 42,
+            "roles": ["admin", "user"],
+            "profile": {"name": "John Doe"}
+        });
+        let log_claims: Vec<String> = vec!["tenant", "roles", "profile", "missing"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let listener: SocketAddr = ([127, 0, 0, 1], 4000).into();
+        let event = UsageEvent::new(listener, &document, counts, claims.as_object(), &log_claims);
+        assert_eq!("mutation", event.operation_type);
+        assert_eq!(Some("AddPost".to_string()), event.operation_name);
+        assert_eq!(1, event.total());
+        assert_eq!(
+            vec![
+                ("tenant".to_string(), "42".to_string()),
+                ("roles".to_string(), "admin,user".to_string())
+            ],
+            event.claims
+        );
+        assert_eq!(Decision::Allowed, event.decision);
+        assert_eq!(None, event.status);
+
+        let anonymous = graphql_parser::parse_query("{ posts { id } }").unwrap();
+        let event = UsageEvent::new(listener, &anonymous, HashMap::new(), None, &log_claims);
+        assert_eq!("query", event.operation_type);
+        assert_eq!(None, event.operation_name);
+        assert!(event.claims.is_empty());
+    }
+
+    #[test]
+    fn test_json_lines_file() {
+        crate::initialize_test_logging();
+        let location = std::env::temp_dir().join(format!("arboric-{}.log", std::process::id()));
+        let location = location.to_str().unwrap();
+        let sink = JsonLines::file(location).unwrap();
+
+        let document = graphql_parser::parse_query("query Feed { posts { id } }").unwrap();
+        let mut counts = HashMap::new();
+        counts.insert("posts".to_string(), 1);
+        let claims = json!({"sub": "1234567890"});
+        let listener: SocketAddr = ([127, 0, 0, 1], 4000).into();
+        let mut usage = UsageEvent::new(
+            listener,
+            &document,
+            counts,
+            claims.as_object(),
+            &["sub".to_string()],
+        );
+        usage.status = Some(200);
+        sink.usage(&usage);
+        sink.refusal(&RefusalEvent {
+            listener,
+            status: 401,
+            code: "UNAUTHENTICATED",
+            decision: None,
+            token_error: Some("expired"),
+            batch_entry: false,
+        });
+
+        let text = std::fs::read_to_string(location).unwrap();
+        std::fs::remove_file(location).unwrap();
+        let lines: Vec<Value> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(2, lines.len());
+        assert_eq!("usage", lines[0]["event"]);
+        assert_eq!("127.0.0.1:4000", lines[0]["listener"]);
+        assert_eq!("Feed", lines[0]["operation_name"]);
+        assert_eq!(json!({"posts": 1}), lines[0]["counts"]);
+        assert_eq!(json!({"sub": "1234567890"}), lines[0]["claims"]);
+        assert_eq!(200, lines[0]["status"]);
+        assert!(lines[0]["timestamp"].is_u64());
+        assert_eq!("refusal", lines[1]["event"]);
+        assert_eq!(Value::Null, lines[1]["decision"]);
+        assert_eq!("expired", lines[1]["token_error"]);
+    }
+}
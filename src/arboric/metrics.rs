@@ -0,0 +1,482 @@
+//! Prometheus metrics. Listeners record what they handle in a shared
+//! [Metrics](arboric::metrics::Metrics) registry, which is served in the Prometheus
+//! text format from its own metrics listener, for Prometheus to scrape.
+
+use super::listener::Serve;
+use super::metering::{Decision, RefusalEvent, Sink, UsageEvent};
+use crate::ArboricError;
+use futures::Future;
+use hyper::service::service_fn_ok;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info, trace};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The path metrics are served from
+pub static METRICS_PATH: &str = "/metrics";
+
+/// The upper bounds, in seconds, of the upstream latency histogram buckets
+static LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// How many distinct `arboric_fields_total` series are kept. Field names come from
+/// callers, so once this many have been seen, any others are counted as
+/// [OTHER_FIELD](OTHER_FIELD) instead.
+pub const MAX_FIELD_SERIES: usize = 1000;
+
+/// The `field` label fields are counted under once there are
+/// [MAX_FIELD_SERIES](MAX_FIELD_SERIES) series
+pub static OTHER_FIELD: &str = "__other__";
+
+/// The registry of metrics, shared by all listeners
+#[derive(Debug, Default)]
+pub struct Metrics {
+    registry: Mutex<Registry>,
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    /// By (listener, status, decision)
+    requests: BTreeMap<(String, String, String), u64>,
+    /// By (listener, operation type, field)
+    fields: BTreeMap<(String, String, String), u64>,
+    /// By listener
+    upstream_latency: BTreeMap<String, Histogram>,
+    /// By (listener, reason)
+    token_failures: BTreeMap<(String, String), u64>,
+    /// By listener
+    in_flight: BTreeMap<String, i64>,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; 11],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|le| seconds <= *le) {
+            self.buckets[i] += 1;
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Records a request that was evaluated. Allowed requests are counted with the
+    /// upstream API's status (empty if it didn't respond), and their fields are
+    /// counted. Denied ones are counted when they're refused, see
+    /// [observe_refusal](#method.observe_refusal)
+    pub fn observe_usage(&self, listener: &str, usage: &UsageEvent) {
+        trace!("observe_usage({:?}, {:?})", listener, usage);
+        self.with_registry(|registry| {
+            if usage.decision == Decision::Allowed {
+                for (field, n) in usage.counts.iter() {
+                    let mut key = (
+                        listener.to_string(),
+                        usage.operation_type.to_string(),
+                        field.clone(),
+                    );
+                    if registry.fields.len() >= MAX_FIELD_SERIES
+                        && !registry.fields.contains_key(&key)
+                    {
+                        key.2 = OTHER_FIELD.to_string();
+                    }
+                    *registry.fields.entry(key).or_insert(0) += *n as u64;
+                }
+                let status = usage.status.map_or_else(String::new, |s| s.to_string());
+                let key = (listener.to_string(), status, "allowed".to_string());
+                *registry.requests.entry(key).or_insert(0) += 1;
+            }
+            if let Some(upstream_latency) = usage.upstream_latency {
+                registry
+                    .upstream_latency
+                    .entry(listener.to_string())
+                    .or_default()
+                    .observe(upstream_latency);
+            }
+        });
+    }
+
+    /// Records a refused request, with the status it was refused with (or
+    /// `"batch_entry_denied"`, see [RefusalEvent::status_label](arboric::metering::RefusalEvent::status_label)),
+    /// and whether the policies `"denied"` it or it was `"refused"` before they were
+    /// evaluated
+    pub fn observe_refusal(&self, listener: &str, status: &str, decision: &str) {
+        self.with_registry(|registry| {
+            let key = (
+                listener.to_string(),
+                status.to_string(),
+                decision.to_string(),
+            );
+            *registry.requests.entry(key).or_insert(0) += 1;
+        });
+    }
+
+    /// Records a bearer token that failed verification or validation, for the given
+    /// reason (see [TokenError::reason](arboric::jwt::TokenError::reason))
+    pub fn observe_token_error(&self, listener: &str, reason: &str) {
+        self.with_registry(|registry| {
+            let key = (listener.to_string(), reason.to_string());
+            *registry.token_failures.entry(key).or_insert(0) += 1;
+        });
+    }
+
+    /// Counts a request as in flight until the returned guard is dropped
+    pub fn in_flight(self: &Arc<Self>, listener: &str) -> InFlight {
+        self.add_in_flight(listener, 1);
+        InFlight {
+            metrics: self.clone(),
+            listener: listener.to_string(),
+        }
+    }
+
+    fn add_in_flight(&self, listener: &str, n: i64) {
+        self.with_registry(|registry| {
+            *registry.in_flight.entry(listener.to_string()).or_insert(0) += n;
+        });
+    }
+
+    fn with_registry<F: FnOnce(&mut Registry)>(&self, f: F) {
+        // A panic while holding the lock can't leave the counters inconsistent
+        let mut registry = match self.registry.lock() {
+            Ok(registry) => registry,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        f(&mut registry);
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        self.with_registry(|registry| {
+            header(
+                &mut text,
+                "arboric_requests_total",
+                "counter",
+                "Requests handled, by upstream (or refusal) status and policy decision",
+            );
+            for ((listener, status, decision), n) in registry.requests.iter() {
+                sample(
+                    &mut text,
+                    "arboric_requests_total",
+                    &[
+                        ("listener", listener.as_str()),
+                        ("status", status.as_str()),
+                        ("decision", decision.as_str()),
+                    ],
+                    *n as f64,
+                );
+            }
+
+            header(
+                &mut text,
+                "arboric_fields_total",
+                "counter",
+                "Times each top level field of an allowed request was selected",
+            );
+            for ((listener, operation_type, field), n) in registry.fields.iter() {
+                sample(
+                    &mut text,
+                    "arboric_fields_total",
+                    &[
+                        ("listener", listener.as_str()),
+                        ("operation_type", operation_type.as_str()),
+                        ("field", field.as_str()),
+                    ],
+                    *n as f64,
+                );
+            }
+
+            header(
+                &mut text,
+                "arboric_upstream_latency_seconds",
+                "histogram",
+                "Time from forwarding a request until the upstream API responded",
+            );
+            for (listener, histogram) in registry.upstream_latency.iter() {
+                let mut cumulative = 0;
+                for (le, n) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                    cumulative += n;
+                    sample(
+                        &mut text,
+                        "arboric_upstream_latency_seconds_bucket",
+                        &[
+                            ("listener", listener.as_str()),
+                            ("le", le.to_string().as_str()),
+                        ],
+                        cumulative as f64,
+                    );
+                }
+                sample(
+                    &mut text,
+                    "arboric_upstream_latency_seconds_bucket",
+                    &[("listener", listener.as_str()), ("le", "+Inf")],
+                    histogram.count as f64,
+                );
+                sample(
+                    &mut text,
+                    "arboric_upstream_latency_seconds_sum",
+                    &[("listener", listener.as_str())],
+                    histogram.sum,
+                );
+                sample(
+                    &mut text,
+                    "arboric_upstream_latency_seconds_count",
+                    &[("listener", listener.as_str())],
+                    histogram.count as f64,
+                );
+            }
+
+            header(
+                &mut text,
+                "arboric_jwt_failures_total",
+                "counter",
+                "Bearer tokens that failed verification or validation, by reason",
+            );
+            for ((listener, reason), n) in registry.token_failures.iter() {
+                sample(
+                    &mut text,
+                    "arboric_jwt_failures_total",
+                    &[("listener", listener.as_str()), ("reason", reason.as_str())],
+                    *n as f64,
+                );
+            }
+
+            header(
+                &mut text,
+                "arboric_requests_in_flight",
+                "gauge",
+                "Requests being handled",
+            );
+            for (listener, n) in registry.in_flight.iter() {
+                sample(
+                    &mut text,
+                    "arboric_requests_in_flight",
+                    &[("listener", listener.as_str())],
+                    *n as f64,
+                );
+            }
+        });
+        text
+    }
+
+    /// Binds the metrics listener to the given address, and returns the future that
+    /// serves `GET /metrics` on it. Like a [Listener](arboric::Listener)'s, the future
+    /// needs to be spawned on (or run by) a Tokio runtime.
+    pub fn serve(self: Arc<Self>, address: SocketAddr) -> crate::Result<Serve> {
+        let bound = Server::try_bind(&address).map_err(|cause| ArboricError::HyperError {
+            message: format!("Unable to bind to {}: {}", &address, cause),
+            cause,
+        })?;
+        info!("Metrics listening on {}", &address);
+        let server = bound
+            .serve(move || {
+                let metrics = self.clone();
+                service_fn_ok(move |req: Request<Body>| metrics.respond(&req))
+            })
+            .map_err(move |e| error!("Metrics listener on {}: server error: {}", &address, e));
+        Ok(Box::new(server))
+    }
+
+    fn respond(&self, req: &Request<Body>) -> Response<Body> {
+        if *req.method() != Method::GET || req.uri().path() != METRICS_PATH {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            return response;
+        }
+        let mut response = Response::new(Body::from(self.render()));
+        response.headers_mut().insert(
+            http::header::CONTENT_TYPE,
+            http::header::HeaderValue::from_static("text/plain; version=0.0.4"),
+        );
+        response
+    }
+}
+
+/// The metrics registry is also a metering sink, so that it can be listed in a
+/// listener's `log_to` like any other
+impl Sink for Metrics {
+    fn usage(&self, usage: &UsageEvent) {
+        self.observe_usage(&usage.listener.to_string(), usage);
+    }
+
+    fn refusal(&self, refusal: &RefusalEvent) {
+        let listener = refusal.listener.to_string();
+        let decision = refusal.decision.map_or("refused", Decision::as_str);
+        self.observe_refusal(&listener, &refusal.status_label(), decision);
+        if let Some(reason) = refusal.token_error {
+            self.observe_token_error(&listener, reason);
+        }
+    }
+}
+
+/// Counts a request as in flight while it's alive, see
+/// [Metrics::in_flight](arboric::metrics::Metrics::in_flight)
+#[derive(Debug)]
+pub struct InFlight {
+    metrics: Arc<Metrics>,
+    listener: String,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.metrics.add_in_flight(&self.listener, -1);
+    }
+}
+
+fn header(text: &mut String, name: &str, metric_type: &str, help: &str) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} {}", name, metric_type);
+}
+
+fn sample(text: &mut String, name: &str, labels: &[(&str, &str)], value: f64) {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(label, value)| format!(r#"{}="{}""#, label, escape(value)))
+        .collect();
+    let _ = writeln!(text, "{}{{{}}} {}", name, labels.join(","), value);
+}
+
+/// Escapes a label value, see https://prometheus.io/docs/instrumenting/exposition_formats/
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    // Import names from outer (for mod tests) scope.
+    use super::*;
+
+    use std::collections::HashMap;
+
+    static LISTENER: &str = "127.0.0.1:4000";
+
+    fn usage(decision: Decision, status: Option<u16>) -> UsageEvent {
+        let document = graphql_parser::parse_query("{ posts { id } users { id } }").unwrap();
+        let mut counts = HashMap::new();
+        counts.insert("posts".to_string(), 2);
+        counts.insert("users".to_string(), 1);
+        let mut usage = UsageEvent::new(LISTENER.parse().unwrap(), &document, counts, None, &[]);
+        usage.decision = decision;
+        usage.status = status;
+        if status.is_some() {
+            usage.upstream_latency = Some(Duration::from_millis(20));
+        }
+        usage
+    }
+
+    fn refusal(
+        status: u16,
+        decision: Option<Decision>,
+        token_error: Option<&'static str>,
+    ) -> RefusalEvent {
+        RefusalEvent {
+            listener: LISTENER.parse().unwrap(),
+            status,
+            code: "CODE",
+            decision,
+            token_error,
+            batch_entry: false,
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        crate::initialize_test_logging();
+        let metrics = Arc::new(Metrics::new());
+        metrics.usage(&usage(Decision::Allowed, Some(200)));
+        metrics.usage(&usage(Decision::Allowed, Some(200)));
+        metrics.usage(&usage(Decision::Denied, None));
+        metrics.refusal(&refusal(403, Some(Decision::Denied), None));
+        metrics.refusal(&refusal(401, None, Some("expired")));
+        let in_flight = metrics.in_flight(LISTENER);
+
+        let text = metrics.render();
+        let lines: Vec<&str> = text.lines().collect();
+        for expected in &[
+            "# TYPE arboric_requests_total counter",
+            r#"arboric_requests_total{listener="127.0.0.1:4000",status="200",decision="allowed"} 2"#,
+            r#"arboric_requests_total{listener="127.0.0.1:4000",status="403",decision="denied"} 1"#,
+            r#"arboric_requests_total{listener="127.0.0.1:4000",status="401",decision="refused"} 1"#,
+            r#"arboric_fields_total{listener="127.0.0.1:4000",operation_type="query",field="posts"} 4"#,
+            r#"arboric_fields_total{listener="127.0.0.1:4000",operation_type="query",field="users"} 2"#,
+            "# TYPE arboric_upstream_latency_seconds histogram",
+            r#"arboric_upstream_latency_seconds_bucket{listener="127.0.0.1:4000",le="0.01"} 0"#,
+            r#"arboric_upstream_latency_seconds_bucket{listener="127.0.0.1:4000",le="0.025"} 2"#,
+            r#"arboric_upstream_latency_seconds_bucket{listener="127.0.0.1:4000",le="+Inf"} 2"#,
+            r#"arboric_upstream_latency_seconds_count{listener="127.0.0.1:4000"} 2"#,
+            r#"arboric_jwt_failures_total{listener="127.0.0.1:4000",reason="expired"} 1"#,
+            r#"arboric_requests_in_flight{listener="127.0.0.1:4000"} 1"#,
+        ] {
+            assert!(lines.contains(expected), "{} not in:\n{}", expected, text);
+        }
+
+        drop(in_flight);
+        assert!(metrics
+            .render()
+            .contains(r#"arboric_requests_in_flight{listener="127.0.0.1:4000"} 0"#));
+    }
+
+    #[test]
+    fn test_field_series_are_bounded() {
+        crate::initialize_test_logging();
+        let metrics = Metrics::new();
+        for i in 0..(MAX_FIELD_SERIES * 3) {
+            let mut usage = usage(Decision::Allowed, Some(200));
+            usage.counts = vec![(format!("f{:x}", i * 7919), 1)].into_iter().collect();
+            metrics.usage(&usage);
+        }
+        // Denied requests' fields aren't counted at all
+        let mut denied = usage(Decision::Denied, None);
+        denied.counts = vec![("denied".to_string(), 1)].into_iter().collect();
+        metrics.usage(&denied);
+
+        let text = metrics.render();
+        let series = text
+            .lines()
+            .filter(|line| line.starts_with("arboric_fields_total{"))
+            .count();
+        assert!(series <= MAX_FIELD_SERIES + 1, "{} series", series);
+        assert!(text.contains(&format!(
+            r#"arboric_fields_total{{listener="127.0.0.1:4000",operation_type="query",field="__other__"}} {}"#,
+            MAX_FIELD_SERIES * 2
+        )));
+        assert!(!text.contains(r#"field="denied""#));
+    }
+
+    #[test]
+    fn test_respond() {
+        crate::initialize_test_logging();
+        let metrics = Metrics::new();
+        let get = Request::get(METRICS_PATH).body(Body::empty()).unwrap();
+        let response = metrics.respond(&get);
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(
+            "text/plain; version=0.0.4",
+            response.headers()[http::header::CONTENT_TYPE]
+        );
+        let other = Request::get("/").body(Body::empty()).unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, metrics.respond(&other).status());
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(r#"a\"b\\c\nd"#, escape("a\"b\\c\nd"));
+    }
+}
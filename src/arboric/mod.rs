@@ -7,31 +7,45 @@ use log::{debug, info, trace, warn};
 use serde::{Deserialize, Serialize};
 use serde_json::value::Value;
 use std::collections::HashMap;
+use std::sync::Arc;
 
 pub mod abac;
 pub mod config;
 pub mod graphql;
 pub mod influxdb;
+pub mod jwt;
+pub mod metering;
+pub mod metrics;
 
 mod error;
 mod listener;
 mod proxy_service;
+mod refusal;
+mod tls;
+mod websocket;
 
 // arboric::ArboricError;
 pub use error::ArboricError;
-// arboric::Listener
-pub use listener::Listener;
+// arboric::Listener and arboric::serve_until
+pub use listener::{serve_until, Listener};
 // arboric::ProxyService
 pub use proxy_service::ProxyService;
 
 type QueryCounts = HashMap<String, usize>;
-type ParsePostResult = crate::Result<Option<(Document, QueryCounts)>>;
+/// The variables given with a GraphQL request
+pub type Variables = HashMap<String, Value>;
+type CountResult = crate::Result<Option<(Document, QueryCounts)>>;
+type ParsePostResult = crate::Result<Option<(Document, QueryCounts, Variables)>>;
+/// A batch of GraphQL requests: each request as it was given, and as parsed
+pub type Batch = Vec<(Value, ParsePostResult)>;
 
 pub fn parse_post(content_type: Option<mime::Mime>, body: &String) -> ParsePostResult {
     trace!("parse_post({:?}, {:?})", &content_type, &body);
     let application_graphql: mime::Mime = "application/graphql".parse().unwrap();
     match content_type {
-        Some(ref mime_type) if &application_graphql == mime_type => count_top_level_fields(body),
+        Some(ref mime_type) if &application_graphql == mime_type => {
+            without_variables(count_top_level_fields(body, None))
+        }
         Some(ref mime_type) if &mime::APPLICATION_JSON == mime_type => {
             match count_json_query(body) {
                 Ok(results) => Ok(results),
@@ -47,43 +61,114 @@ pub fn parse_post(content_type: Option<mime::Mime>, body: &String) -> ParsePostR
         }
         None => {
             warn!("No content-type specified, will try to parse as application/graphql");
-            count_top_level_fields(body)
+            without_variables(count_top_level_fields(body, None))
+        }
+    }
+}
+
+/// Parses the `query`, `operationName` and `variables` parameters of a GET request's
+/// query string. Returns `None` if there's no `query` parameter.
+pub fn parse_get(query_string: Option<&str>) -> ParsePostResult {
+    trace!("parse_get({:?})", &query_string);
+    let mut query: Option<String> = None;
+    let mut operation_name: Option<String> = None;
+    let mut variables: Option<String> = None;
+    for (key, value) in url::form_urlencoded::parse(query_string.unwrap_or("").as_bytes()) {
+        match key.as_ref() {
+            "query" => query = Some(value.into_owned()),
+            "operationName" if !value.is_empty() => operation_name = Some(value.into_owned()),
+            "variables" if !value.is_empty() => variables = Some(value.into_owned()),
+            _ => (),
         }
     }
+    let query = match query {
+        Some(query) => query,
+        None => return Ok(None),
+    };
+    let variables = match variables {
+        Some(ref variables) => serde_json::from_str::<Option<Variables>>(variables)?,
+        None => None,
+    };
+    count_top_level_fields(&query, operation_name.as_deref()).map(|parsed| {
+        parsed.map(|(document, counts)| (document, counts, variables.unwrap_or_default()))
+    })
+}
+
+/// Parses a JSON body that's an array of GraphQL requests, i.e. a batch (as sent by
+/// e.g. Apollo's `BatchHttpLink`), parsing each request on its own. Returns `None` if
+/// the body isn't a JSON array.
+pub fn parse_batch(content_type: Option<&mime::Mime>, body: &str) -> crate::Result<Option<Batch>> {
+    trace!("parse_batch({:?}, {:?})", &content_type, &body);
+    match content_type {
+        Some(mime_type) if &mime::APPLICATION_JSON == mime_type => (),
+        _ => return Ok(None),
+    }
+    if !body.trim_start().starts_with('[') {
+        return Ok(None);
+    }
+    let requests: Vec<Value> = serde_json::from_str(body)?;
+    Ok(Some(
+        requests
+            .into_iter()
+            .map(|request| {
+                let parsed = count_json_value(request.clone());
+                (request, parsed)
+            })
+            .collect(),
+    ))
+}
+
+fn without_variables(count_result: CountResult) -> ParsePostResult {
+    count_result.map(|parsed| parsed.map(|(document, counts)| (document, counts, Variables::new())))
 }
 
-pub fn log_counts(influx_db_backend: &influxdb::Backend, map: &QueryCounts) {
-    trace!("log_counts({:?}, {:?}", &influx_db_backend, &map);
-    let total: usize = map.values().sum();
+/// Logs the counts of the top level fields of a request's operation, and sends its
+/// usage to each of the given sinks
+pub fn log_counts(sinks: &[Arc<dyn metering::Sink>], usage: &metering::UsageEvent) {
+    trace!("log_counts({:?}, {:?}", &sinks, &usage);
     info!(
-        "Found {} ({} unique) fields/queries",
-        total,
-        map.keys().count()
+        "Found {} ({} unique) {} fields",
+        usage.total(),
+        usage.counts.len(),
+        usage.operation_type
     );
 
-    influx_db_backend.write_points(map);
+    for sink in sinks.iter() {
+        sink.usage(usage);
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct GraphQLJSONQuery {
     query: String,
+    #[serde(rename = "operationName")]
     operation_name: Option<String>,
-    variables: Option<HashMap<String, Value>>,
+    variables: Option<Variables>,
 }
 
 fn count_json_query(body: &str) -> ParsePostResult {
     trace!("count_json_query({})", &body);
-    let q: GraphQLJSONQuery = serde_json::from_str(body)?;
+    count_json_value(serde_json::from_str(body)?)
+}
+
+fn count_json_value(value: Value) -> ParsePostResult {
+    let q: GraphQLJSONQuery = serde_json::from_value(value)?;
     trace!("{:?}", &q);
     trace!("{}", &q.query);
-    count_top_level_fields(q.query.as_str())
+    let variables = q.variables.unwrap_or_default();
+    count_top_level_fields(q.query.as_str(), q.operation_name.as_deref())
+        .map(|parsed| parsed.map(|(document, counts)| (document, counts, variables)))
 }
 
-/// Counts the top level fields in the given GraphQL query string
-fn count_top_level_fields(query: &str) -> ParsePostResult {
-    trace!("count_top_level_fields({:?})", &query);
+/// Counts the top level fields of the selected operation (see
+/// [select_operation](arboric::graphql::select_operation)) in the given GraphQL query
+/// string, including those selected through fragments. Returns a document with only
+/// that operation, and its fragments expanded.
+fn count_top_level_fields(query: &str, operation_name: Option<&str>) -> CountResult {
+    trace!("count_top_level_fields({:?}, {:?})", &query, operation_name);
     let mut results: HashMap<String, usize> = HashMap::new();
-    let document = parse_query(&query)?;
+    let selected = graphql::select_operation(&parse_query(query)?, operation_name)?;
+    let document = graphql::expand_fragments(&selected)?;
 
     trace!("document => {:?}", &document);
     for def in document.definitions.iter() {
@@ -95,27 +180,32 @@ fn count_top_level_fields(query: &str) -> ParsePostResult {
                 update_results(&mut results, &query.selection_set);
             }
             Operation(OperationDefinition::SelectionSet(selection_set)) => {
-                update_results(&mut results, &selection_set);
+                update_results(&mut results, selection_set);
+            }
+            Operation(OperationDefinition::Mutation(mutation)) => {
+                if let Some(mutation_name) = &mutation.name {
+                    debug!("mutation.name => {}", mutation_name);
+                }
+                update_results(&mut results, &mutation.selection_set);
+            }
+            Operation(OperationDefinition::Subscription(subscription)) => {
+                if let Some(subscription_name) = &subscription.name {
+                    debug!("subscription.name => {}", subscription_name);
+                }
+                update_results(&mut results, &subscription.selection_set);
             }
             _ => warn!("{:?}", def),
         }
     }
 
-    return Ok(Some((document, results)));
+    Ok(Some((document, results)))
 }
 
 fn update_results(results: &mut HashMap<String, usize>, selection_set: &SelectionSet) {
-    for selection in selection_set.items.iter() {
-        match selection {
-            graphql_parser::query::Selection::Field(field) => {
-                trace!("field.name => {}", &field.name);
-                let n = results.entry(field.name.clone()).or_insert(0);
-                *n += 1;
-            }
-            _ => {
-                trace!("{:?}", selection);
-            }
-        }
+    for field in graphql::fields(selection_set) {
+        trace!("field.name => {}", &field.name);
+        let n = results.entry(field.name.clone()).or_insert(0);
+        *n += 1;
     }
 }
 
@@ -129,7 +219,7 @@ mod tests {
         crate::initialize_test_logging();
         let mut expected: QueryCounts = HashMap::new();
         expected.insert("foo".into(), 1);
-        let (_, counts) = count_top_level_fields("{foo{id}}").unwrap().unwrap();
+        let (_, counts) = count_top_level_fields("{foo{id}}", None).unwrap().unwrap();
         assert_eq!(counts, expected);
         let q = "
         {
@@ -142,7 +232,108 @@ mod tests {
         }
         ";
         expected.insert("bar".into(), 1);
-        let (_, counts2) = count_top_level_fields(&q).unwrap().unwrap();
+        let (_, counts2) = count_top_level_fields(q, None).unwrap().unwrap();
         assert_eq!(counts2, expected);
     }
+
+    #[test]
+    fn test_count_top_level_fields_in_fragments() {
+        crate::initialize_test_logging();
+        let mut expected: QueryCounts = HashMap::new();
+        expected.insert("foo".into(), 2);
+        expected.insert("bar".into(), 1);
+        let q = "{foo{id} ...F ... on Query {foo{name}}} fragment F on Query {bar{id}}";
+        let (document, counts) = count_top_level_fields(q, None).unwrap().unwrap();
+        assert_eq!(counts, expected);
+        assert!(!graphql::has_fragments(&document));
+        assert!(count_top_level_fields("{...A} fragment A on Query {...A}", None).is_err());
+    }
+
+    #[test]
+    fn test_count_mutation_fields() {
+        crate::initialize_test_logging();
+        let mut expected: QueryCounts = HashMap::new();
+        expected.insert("createReview".into(), 2);
+        expected.insert("deleteReview".into(), 1);
+        let q = "mutation { a: createReview(stars: 5) { id } b: createReview(stars: 1) { id } deleteReview(id: 1) }";
+        let (document, counts) = count_top_level_fields(q, None).unwrap().unwrap();
+        assert_eq!(counts, expected);
+        assert_eq!(
+            Some("mutation"),
+            graphql::document_operation_type(&document)
+        );
+    }
+
+    #[test]
+    fn test_count_subscription_fields() {
+        crate::initialize_test_logging();
+        let mut expected: QueryCounts = HashMap::new();
+        expected.insert("reviewAdded".into(), 1);
+        let (_, counts) = count_top_level_fields("subscription { reviewAdded { stars } }", None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(counts, expected);
+    }
+
+    #[test]
+    fn test_count_json_query_selects_operation() {
+        crate::initialize_test_logging();
+        let body = r#"{
+            "query": "query Public { publicPosts { title } } query Secret { secrets { value } ...F } fragment F on Query { more }",
+            "operationName": "Public"
+        }"#;
+        let (document, counts, _) = count_json_query(body).unwrap().unwrap();
+        let mut expected: QueryCounts = HashMap::new();
+        expected.insert("publicPosts".into(), 1);
+        assert_eq!(counts, expected);
+        assert_eq!(1, document.definitions.len());
+
+        let ambiguous = r#"{"query": "query A { a } query B { b }"}"#;
+        assert!(count_json_query(ambiguous).is_err());
+        let unknown = r#"{"query": "query A { a }", "operationName": "B"}"#;
+        assert!(count_json_query(unknown).is_err());
+    }
+
+    #[test]
+    fn test_parse_batch() {
+        crate::initialize_test_logging();
+        let json = Some(&mime::APPLICATION_JSON);
+        let body = r#"[{"query": "{a}"}, {"query": "{b"}, {"query": "query A {c} query B {d}", "operationName": "B"}]"#;
+        let batch = parse_batch(json, body).unwrap().unwrap();
+        assert_eq!(3, batch.len());
+        assert_eq!(serde_json::json!({"query": "{a}"}), batch[0].0);
+        let (_, counts, _) = batch[0].1.as_ref().unwrap().as_ref().unwrap();
+        assert_eq!(Some(&1), counts.get("a"));
+        assert!(batch[1].1.is_err());
+        let (_, counts, _) = batch[2].1.as_ref().unwrap().as_ref().unwrap();
+        assert_eq!(Some(&1), counts.get("d"));
+
+        assert!(parse_batch(json, r#"{"query": "{a}"}"#).unwrap().is_none());
+        assert!(parse_batch(None, r#"[{"query": "{a}"}]"#)
+            .unwrap()
+            .is_none());
+        assert!(parse_batch(json, "[{").is_err());
+    }
+
+    #[test]
+    fn test_parse_get() {
+        crate::initialize_test_logging();
+        let query_string =
+            "query=query%20A%20%7B%20a%20%7D%20query%20B%20%7B%20b(id%3A%20%24id)%20%7D\
+                            &operationName=B&variables=%7B%22id%22%3A1%7D";
+        let (document, counts, variables) = parse_get(Some(query_string)).unwrap().unwrap();
+        let mut expected: QueryCounts = HashMap::new();
+        expected.insert("b".into(), 1);
+        assert_eq!(counts, expected);
+        assert_eq!(1, document.definitions.len());
+        assert_eq!(Some(&serde_json::json!(1)), variables.get("id"));
+
+        let (_, counts, variables) = parse_get(Some("query={a}&variables=")).unwrap().unwrap();
+        assert_eq!(Some(&1), counts.get("a"));
+        assert!(variables.is_empty());
+
+        assert!(parse_get(None).unwrap().is_none());
+        assert!(parse_get(Some("operationName=A")).unwrap().is_none());
+        assert!(parse_get(Some("query={a}&variables=nope")).is_err());
+    }
 }
@@ -0,0 +1,289 @@
+//! A Refusal is the reason Arboric refuses to forward a request. It's sent to the
+//! caller as a GraphQL style `{"errors": [...]}` response, so that GraphQL clients
+//! can show something more helpful than a network error.
+
+use crate::config::ErrorVerbosity;
+use crate::jwt::TokenError;
+use hyper::{Body, Response, StatusCode};
+use serde_json::json;
+
+static REALM: &str = "arboric";
+
+#[derive(Debug)]
+pub enum Refusal {
+    /// The request body couldn't be parsed as a GraphQL request
+    ParseFailed(String),
+    /// The document isn't valid against the upstream API's schema, for these reasons
+    ValidationFailed(Vec<String>),
+    /// The batch has more requests than the listener allows: (size, max_size)
+    BatchTooLarge(usize, usize),
+    /// The request has a content type we don't know how to parse
+    UnsupportedContentType(String),
+    /// The WebSocket upgrade request doesn't offer a GraphQL subprotocol we speak
+    UnsupportedProtocol(String),
+    /// The request has no credentials, and anonymous requests aren't allowed
+    MissingToken,
+    /// The bearer token isn't valid
+    InvalidToken(TokenError),
+    /// The policies don't allow the request
+    DeniedByPolicy,
+    /// A GET request for an operation other than a query, e.g. a `mutation`
+    MethodNotAllowed(String),
+    NotFound,
+}
+
+impl Refusal {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Refusal::ParseFailed(_)
+            | Refusal::ValidationFailed(_)
+            | Refusal::BatchTooLarge(_, _)
+            | Refusal::UnsupportedContentType(_)
+            | Refusal::UnsupportedProtocol(_) => StatusCode::BAD_REQUEST,
+            Refusal::MissingToken | Refusal::InvalidToken(_) => StatusCode::UNAUTHORIZED,
+            // The caller is authenticated (or anonymous), but isn't allowed to do this
+            Refusal::DeniedByPolicy => StatusCode::FORBIDDEN,
+            Refusal::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            Refusal::NotFound => StatusCode::NOT_FOUND,
+        }
+    }
+
+    /// The `extensions.code` of the error, using the codes Apollo Server uses where
+    /// there's an equivalent
+    pub fn code(&self) -> &'static str {
+        match self {
+            Refusal::ParseFailed(_) => "GRAPHQL_PARSE_FAILED",
+            Refusal::ValidationFailed(_) => "GRAPHQL_VALIDATION_FAILED",
+            Refusal::BatchTooLarge(_, _)
+            | Refusal::UnsupportedContentType(_)
+            | Refusal::UnsupportedProtocol(_)
+            | Refusal::MethodNotAllowed(_) => "BAD_REQUEST",
+            Refusal::MissingToken | Refusal::InvalidToken(_) => "UNAUTHENTICATED",
+            Refusal::DeniedByPolicy => "FORBIDDEN",
+            Refusal::NotFound => "NOT_FOUND",
+        }
+    }
+
+    /// A message that says why the request was refused, without any details
+    /// of the request itself
+    pub fn message(&self) -> String {
+        match self {
+            Refusal::ParseFailed(_) => "Unable to parse GraphQL request".into(),
+            Refusal::ValidationFailed(_) => "GraphQL request failed validation".into(),
+            Refusal::BatchTooLarge(size, max_size) => format!(
+                "Batch of {} requests is larger than the maximum of {}",
+                size, max_size
+            ),
+            Refusal::UnsupportedContentType(_) => "Unsupported content type".into(),
+            Refusal::UnsupportedProtocol(_) => "Unsupported WebSocket subprotocol".into(),
+            Refusal::MissingToken => "Missing bearer token".into(),
+            Refusal::InvalidToken(token_error) => token_error.to_string(),
+            Refusal::DeniedByPolicy => "Denied by policy".into(),
+            Refusal::MethodNotAllowed(operation_type) => {
+                format!(
+                    "Can only perform a {} operation from a POST request",
+                    operation_type
+                )
+            }
+            Refusal::NotFound => "Not found".into(),
+        }
+    }
+
+    /// The messages for each error in the response: one for each problem a failed
+    /// validation found, or just the [message](#method.message)
+    pub fn messages(&self) -> Vec<String> {
+        match self {
+            Refusal::ValidationFailed(errors) if !errors.is_empty() => errors.clone(),
+            _ => vec![self.message()],
+        }
+    }
+
+    /// Any further details, e.g. the parser error
+    pub fn detail(&self) -> Option<String> {
+        match self {
+            Refusal::ParseFailed(detail)
+            | Refusal::UnsupportedContentType(detail)
+            | Refusal::UnsupportedProtocol(detail) => Some(detail.clone()),
+            Refusal::InvalidToken(token_error) => Some(format!("{:?}", token_error)),
+            _ => None,
+        }
+    }
+
+    /// The `WWW-Authenticate` challenge (RFC 6750) for refusals that can be remedied
+    /// by (re-)authenticating
+    pub fn www_authenticate(&self) -> Option<String> {
+        match self {
+            Refusal::MissingToken => Some(format!(r#"Bearer realm="{}""#, REALM)),
+            Refusal::InvalidToken(token_error) => Some(format!(
+                r#"Bearer realm="{}", error="invalid_token", error_description="{}""#,
+                REALM,
+                token_error.to_string().replace('"', "'")
+            )),
+            _ => None,
+        }
+    }
+
+    /// The GraphQL errors that say why the request was refused, with the given
+    /// verbosity:
+    ///
+    /// * `Minimal` only gives the HTTP status as the message, and the code
+    /// * `Normal` gives the reason as the message, or one error for each validation error
+    /// * `Verbose` also gives any further details
+    pub fn errors(&self, verbosity: ErrorVerbosity) -> Vec<serde_json::Value> {
+        let status_code = self.status_code();
+        let messages = match verbosity {
+            ErrorVerbosity::Minimal => vec![status_code
+                .canonical_reason()
+                .unwrap_or_else(|| status_code.as_str())
+                .to_string()],
+            _ => self.messages(),
+        };
+        let mut extensions = json!({ "code": self.code() });
+        if let ErrorVerbosity::Verbose = verbosity {
+            if let Some(detail) = self.detail() {
+                extensions["detail"] = json!(detail);
+            }
+        }
+        messages
+            .into_iter()
+            .map(|message| {
+                json!({
+                    "message": message,
+                    "extensions": extensions,
+                })
+            })
+            .collect()
+    }
+
+    /// Renders this Refusal as a JSON response, with its [errors](#method.errors)
+    pub fn to_response(&self, verbosity: ErrorVerbosity) -> Response<Body> {
+        let status_code = self.status_code();
+        let errors = self.errors(verbosity);
+        let body = json!({ "errors": errors });
+        let mut response = Response::new(Body::from(body.to_string()));
+        *response.status_mut() = status_code;
+        response.headers_mut().insert(
+            http::header::CONTENT_TYPE,
+            http::header::HeaderValue::from_static("application/json"),
+        );
+        if let Refusal::MethodNotAllowed(_) = self {
+            response.headers_mut().insert(
+                http::header::ALLOW,
+                http::header::HeaderValue::from_static("POST"),
+            );
+        }
+        if let Some(challenge) = self.www_authenticate() {
+            if let Ok(header_value) = http::header::HeaderValue::from_str(&challenge) {
+                response
+                    .headers_mut()
+                    .insert(http::header::WWW_AUTHENTICATE, header_value);
+            }
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Import names from outer (for mod tests) scope.
+    use super::*;
+
+    use futures::{Future, Stream};
+
+    fn body_json(response: Response<Body>) -> serde_json::Value {
+        let chunk = response.into_body().concat2().wait().unwrap();
+        serde_json::from_slice(&chunk).unwrap()
+    }
+
+    #[test]
+    fn test_refusal_to_response() {
+        let refusal = Refusal::InvalidToken(TokenError::Expired);
+
+        let response = refusal.to_response(ErrorVerbosity::Minimal);
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        assert_eq!(
+            json!({"errors": [{"message": "Unauthorized", "extensions": {"code": "UNAUTHENTICATED"}}]}),
+            body_json(response)
+        );
+
+        let response = refusal.to_response(ErrorVerbosity::Normal);
+        assert_eq!(
+            json!({"errors": [{"message": "Token has expired", "extensions": {"code": "UNAUTHENTICATED"}}]}),
+            body_json(response)
+        );
+
+        let response = refusal.to_response(ErrorVerbosity::Verbose);
+        assert_eq!(
+            json!({"errors": [{
+                "message": "Token has expired",
+                "extensions": {"code": "UNAUTHENTICATED", "detail": "Expired"}
+            }]}),
+            body_json(response)
+        );
+    }
+
+    #[test]
+    fn test_refusal_status_and_www_authenticate() {
+        let response = Refusal::MissingToken.to_response(ErrorVerbosity::Normal);
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        assert_eq!(
+            r#"Bearer realm="arboric""#,
+            response.headers()[http::header::WWW_AUTHENTICATE]
+        );
+
+        let response =
+            Refusal::InvalidToken(TokenError::Expired).to_response(ErrorVerbosity::Normal);
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        assert_eq!(
+            r#"Bearer realm="arboric", error="invalid_token", error_description="Token has expired""#,
+            response.headers()[http::header::WWW_AUTHENTICATE]
+        );
+
+        let response = Refusal::DeniedByPolicy.to_response(ErrorVerbosity::Normal);
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+        assert!(response
+            .headers()
+            .get(http::header::WWW_AUTHENTICATE)
+            .is_none());
+    }
+
+    #[test]
+    fn test_validation_failed_gives_an_error_for_each() {
+        let refusal = Refusal::ValidationFailed(vec![
+            r#"Cannot query field "foo" on type "Query"."#.into(),
+            r#"Unknown type "Bar"."#.into(),
+        ]);
+        let response = refusal.to_response(ErrorVerbosity::Normal);
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        assert_eq!(
+            json!({"errors": [
+                {
+                    "message": r#"Cannot query field "foo" on type "Query"."#,
+                    "extensions": {"code": "GRAPHQL_VALIDATION_FAILED"}
+                },
+                {
+                    "message": r#"Unknown type "Bar"."#,
+                    "extensions": {"code": "GRAPHQL_VALIDATION_FAILED"}
+                }
+            ]}),
+            body_json(response)
+        );
+        let minimal = body_json(refusal.to_response(ErrorVerbosity::Minimal));
+        assert_eq!(1, minimal["errors"].as_array().unwrap().len());
+    }
+
+    #[test]
+    fn test_parse_failed_detail_is_verbose_only() {
+        let refusal = Refusal::ParseFailed("Unexpected `}`".into());
+        let normal = body_json(refusal.to_response(ErrorVerbosity::Normal));
+        assert_eq!(
+            json!({"code": "GRAPHQL_PARSE_FAILED"}),
+            normal["errors"][0]["extensions"]
+        );
+        let verbose = body_json(refusal.to_response(ErrorVerbosity::Verbose));
+        assert_eq!(
+            json!("Unexpected `}`"),
+            verbose["errors"][0]["extensions"]["detail"]
+        );
+    }
+}
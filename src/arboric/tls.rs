@@ -0,0 +1,647 @@
+//! TLS support for arboric listeners, built on OpenSSL
+
+use crate::config::{ClientCertificateClaims, TlsConfig, UpstreamTlsConfig};
+use crate::ArboricError;
+use crate::Claims;
+use futures::Future;
+use http::Uri;
+use hyper::client::connect::{Connect, Destination};
+use hyper::client::HttpConnector;
+use hyper::Client;
+use hyper_openssl::HttpsConnector;
+use log::trace;
+use openssl::error::ErrorStack;
+use openssl::ssl::{
+    self, AlpnError, SslAcceptor, SslConnector, SslConnectorBuilder, SslFiletype, SslMethod,
+    SslRef, SslVerifyMode,
+};
+use openssl::x509::{X509Name, X509NameRef, X509Ref};
+use serde_json::Value;
+use std::net::IpAddr;
+use tokio::net::TcpStream;
+use tokio_openssl::{SslConnectorExt, SslStream};
+use tokio_tungstenite::stream::Stream as StreamSwitcher;
+
+/// The hyper Client used to talk to the upstream API, over either HTTP or HTTPS
+pub type HttpsClient = Client<HttpsConnector<HttpConnector>>;
+
+/// A connection to the upstream API for a WebSocket, over TLS for `https://` upstreams
+pub type UpstreamStream = StreamSwitcher<TcpStream, SslStream<TcpStream>>;
+
+/// The ALPN protocols we offer when HTTP/2 is enabled, in wire format and
+/// in order of preference
+static ALPN_H2_HTTP1: &[u8] = b"\x02h2\x08http/1.1";
+
+/// The ALPN protocol id for HTTP/2
+pub static ALPN_H2: &[u8] = b"h2";
+
+/// Builds an [SslAcceptor](openssl::ssl::SslAcceptor) from the given
+/// [TlsConfig](arboric::config::TlsConfig)
+pub fn acceptor(tls_config: &TlsConfig) -> crate::Result<SslAcceptor> {
+    trace!("acceptor({:?})", &tls_config);
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder
+        .set_certificate_chain_file(&tls_config.certificate_chain)
+        .map_err(|cause| {
+            openssl_error(
+                format!(
+                    "Unable to load certificate chain from {}",
+                    &tls_config.certificate_chain
+                ),
+                cause,
+            )
+        })?;
+    builder
+        .set_private_key_file(&tls_config.private_key, SslFiletype::PEM)
+        .map_err(|cause| {
+            openssl_error(
+                format!(
+                    "Unable to load private key from {}",
+                    &tls_config.private_key
+                ),
+                cause,
+            )
+        })?;
+    builder.check_private_key().map_err(|cause| {
+        openssl_error(
+            format!(
+                "Private key {} does not match certificate {}",
+                &tls_config.private_key, &tls_config.certificate_chain
+            ),
+            cause,
+        )
+    })?;
+    if let Some(ref client_auth) = tls_config.client_auth {
+        let load_error = |cause| {
+            openssl_error(
+                format!(
+                    "Unable to load client CA bundle from {}",
+                    &client_auth.ca_bundle
+                ),
+                cause,
+            )
+        };
+        builder
+            .set_ca_file(&client_auth.ca_bundle)
+            .map_err(load_error)?;
+        builder.set_client_ca_list(
+            X509Name::load_client_ca_file(&client_auth.ca_bundle).map_err(load_error)?,
+        );
+        // Needed for session resumption to work with client certificates
+        builder.set_session_id_context(b"arboric")?;
+        builder.set_verify(if client_auth.required {
+            SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT
+        } else {
+            SslVerifyMode::PEER
+        });
+    }
+    if tls_config.http2 {
+        builder.set_alpn_select_callback(|_, client_protocols| {
+            ssl::select_next_proto(ALPN_H2_HTTP1, client_protocols).ok_or(AlpnError::NOACK)
+        });
+    }
+    Ok(builder.build())
+}
+
+/// Returns the claims for the verified client certificate on the given TLS connection,
+/// if the client presented one
+pub fn peer_certificate_claims(ssl: &SslRef, mapping: &ClientCertificateClaims) -> Option<Claims> {
+    ssl.peer_certificate()
+        .map(|certificate| certificate_claims(&certificate, mapping))
+}
+
+/// Maps the subject, subject alternative names, and issuer of the given certificate
+/// to claims, named according to the given mapping
+pub fn certificate_claims(certificate: &X509Ref, mapping: &ClientCertificateClaims) -> Claims {
+    let mut claims = Claims::new();
+    claims.insert(
+        mapping.subject.clone(),
+        Value::String(distinguished_name(certificate.subject_name())),
+    );
+    if let Some(subject_alt_names) = certificate.subject_alt_names() {
+        let names: Vec<String> = subject_alt_names
+            .iter()
+            .filter_map(|general_name| {
+                general_name
+                    .dnsname()
+                    .or_else(|| general_name.uri())
+                    .or_else(|| general_name.email())
+                    .map(String::from)
+                    .or_else(|| general_name.ipaddress().and_then(ip_address_to_string))
+            })
+            .collect();
+        if !names.is_empty() {
+            claims.insert(
+                mapping.subject_alt_names.clone(),
+                Value::Array(names.into_iter().map(Value::String).collect()),
+            );
+        }
+    }
+    claims.insert(
+        mapping.issuer.clone(),
+        Value::String(distinguished_name(certificate.issuer_name())),
+    );
+    trace!("certificate_claims() => {:?}", &claims);
+    claims
+}
+
+/// Formats an X.509 name as an RFC 4514 style string, e.g. `"CN=client,O=Example"`
+fn distinguished_name(name: &X509NameRef) -> String {
+    let mut rdns: Vec<String> = name
+        .entries()
+        .map(|entry| {
+            let key = entry.object().nid().short_name().unwrap_or("?");
+            let value = match entry.data().to_string() {
+                Ok(s) => s,
+                Err(_) => String::from_utf8_lossy(entry.data().as_slice()).to_string(),
+            };
+            format!("{}={}", key, escape_attribute_value(&value))
+        })
+        .collect();
+    // Certificates store the most significant RDN first, RFC 4514 writes it last
+    rdns.reverse();
+    rdns.join(",")
+}
+
+/// Escapes an attribute value as described in RFC 4514, section 2.4
+fn escape_attribute_value(value: &str) -> String {
+    let last = value.chars().count().saturating_sub(1);
+    let mut escaped = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            '"' | '+' | ',' | ';' | '<' | '=' | '>' | '\\' => escaped.push('\\'),
+            '#' if i == 0 => escaped.push('\\'),
+            ' ' if i == 0 || i == last => escaped.push('\\'),
+            '\0' => {
+                escaped.push_str("\\00");
+                continue;
+            }
+            _ => (),
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn ip_address_to_string(bytes: &[u8]) -> Option<String> {
+    match bytes.len() {
+        4 => {
+            let mut octets = [0u8; 4];
+            octets.copy_from_slice(bytes);
+            Some(IpAddr::from(octets).to_string())
+        }
+        16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(bytes);
+            Some(IpAddr::from(octets).to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Builds the [HttpsClient](arboric::tls::HttpsClient) used to talk to the upstream
+/// API. Without an [UpstreamTlsConfig](arboric::config::UpstreamTlsConfig), `https://`
+/// upstreams are verified against the system's trusted CAs.
+pub fn upstream_client(upstream_tls: Option<&UpstreamTlsConfig>) -> crate::Result<HttpsClient> {
+    trace!("upstream_client({:?})", &upstream_tls);
+    let (ssl, server_name) = upstream_ssl(upstream_tls)?;
+    let mut https = HttpsConnector::with_connector(upstream_http(), ssl)?;
+    if let Some(server_name) = server_name {
+        // Send the override as the SNI hostname, and verify the upstream's
+        // certificate against it instead of the host in the upstream URI
+        https.set_callback(move |config, _| {
+            config.set_use_server_name_indication(false);
+            config.set_verify_hostname(false);
+            config.set_hostname(&server_name)?;
+            config.param_mut().set_host(&server_name)
+        });
+    }
+    Ok(Client::builder().build(https))
+}
+
+/// Opens the connections that WebSockets to the upstream API are relayed over. They
+/// use the same [UpstreamTlsConfig](arboric::config::UpstreamTlsConfig) as the
+/// [HttpsClient](arboric::tls::HttpsClient), but never negotiate HTTP/2.
+#[derive(Clone, Debug)]
+pub struct UpstreamConnector {
+    http: HttpConnector,
+    ssl: SslConnector,
+    server_name: Option<String>,
+}
+
+impl UpstreamConnector {
+    pub fn new(upstream_tls: Option<&UpstreamTlsConfig>) -> crate::Result<Self> {
+        trace!("UpstreamConnector::new({:?})", &upstream_tls);
+        let (ssl, server_name) = upstream_ssl(upstream_tls)?;
+        Ok(UpstreamConnector {
+            http: upstream_http(),
+            ssl: ssl.build(),
+            server_name,
+        })
+    }
+
+    /// Connects to the host and port of the given upstream API URI, performing the
+    /// TLS handshake if it's an `https://` URI
+    pub fn connect(
+        &self,
+        uri: &Uri,
+    ) -> Box<dyn Future<Item = UpstreamStream, Error = ArboricError> + Send> {
+        trace!("connect({})", uri);
+        let destination = match Destination::try_from_uri(uri.clone()) {
+            Ok(destination) => destination,
+            Err(cause) => {
+                return Box::new(futures::future::err(ArboricError::HyperError {
+                    message: format!("Unable to connect to {}: {}", uri, cause),
+                    cause,
+                }))
+            }
+        };
+        let tls = destination.scheme() == "https";
+        let host = self
+            .server_name
+            .clone()
+            .unwrap_or_else(|| destination.host().to_string());
+        let ssl = self.ssl.clone();
+        let uri = uri.clone();
+        let connecting = self.http.connect(destination).map_err({
+            let uri = uri.clone();
+            move |cause| ArboricError::IoError {
+                message: format!("Unable to connect to {}: {}", uri, cause),
+                cause,
+            }
+        });
+        Box::new(connecting.and_then(move |(tcp_stream, _)| {
+            let connected: Box<dyn Future<Item = UpstreamStream, Error = ArboricError> + Send> =
+                if tls {
+                    Box::new(
+                        ssl.connect_async(&host, tcp_stream)
+                            .map(StreamSwitcher::Tls)
+                            .map_err(move |e| {
+                                ArboricError::general(format!(
+                                    "TLS handshake with {} failed: {}",
+                                    uri, e
+                                ))
+                            }),
+                    )
+                } else {
+                    Box::new(futures::future::ok(StreamSwitcher::Plain(tcp_stream)))
+                };
+            connected
+        }))
+    }
+}
+
+fn upstream_http() -> HttpConnector {
+    let mut http = HttpConnector::new(4);
+    http.enforce_http(false);
+    http
+}
+
+/// Configures the trust, and any client certificate, for upstream TLS connections.
+/// Also returns the server name to use instead of the upstream host, if any.
+fn upstream_ssl(
+    upstream_tls: Option<&UpstreamTlsConfig>,
+) -> crate::Result<(SslConnectorBuilder, Option<String>)> {
+    let mut ssl = SslConnector::builder(SslMethod::tls())?;
+    let mut server_name: Option<String> = None;
+    if let Some(upstream_tls) = upstream_tls {
+        if let Some(ref ca_bundle) = upstream_tls.ca_bundle {
+            ssl.set_ca_file(ca_bundle).map_err(|cause| {
+                openssl_error(
+                    format!("Unable to load CA bundle from {}", ca_bundle),
+                    cause,
+                )
+            })?;
+        }
+        match (
+            &upstream_tls.client_certificate,
+            &upstream_tls.client_key,
+        ) {
+            (Some(client_certificate), Some(client_key)) => {
+                ssl.set_certificate_chain_file(client_certificate)
+                    .map_err(|cause| {
+                        openssl_error(
+                            format!(
+                                "Unable to load client certificate from {}",
+                                client_certificate
+                            ),
+                            cause,
+                        )
+                    })?;
+                ssl.set_private_key_file(client_key, SslFiletype::PEM)
+                    .map_err(|cause| {
+                        openssl_error(
+                            format!("Unable to load client key from {}", client_key),
+                            cause,
+                        )
+                    })?;
+                ssl.check_private_key().map_err(|cause| {
+                    openssl_error(
+                        format!(
+                            "Client key {} does not match certificate {}",
+                            client_key, client_certificate
+                        ),
+                        cause,
+                    )
+                })?;
+            }
+            (None, None) => (),
+            _ => {
+                return Err(ArboricError::general(
+                    "Both client_certificate and client_key are required for upstream TLS client authentication!",
+                ))
+            }
+        }
+        server_name = upstream_tls.server_name.clone();
+    }
+
+    Ok((ssl, server_name))
+}
+
+fn openssl_error(message: String, cause: ErrorStack) -> ArboricError {
+    ArboricError::OpenSslError {
+        message: format!("{}: {}", message, cause),
+        cause,
+    }
+}
+
+/// Test support for generating throwaway certificates and keys
+#[cfg(test)]
+pub mod test_support {
+    use crate::config::TlsConfig;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::{X509Name, X509};
+    use std::path::PathBuf;
+
+    /// Generates a new 2048-bit RSA key pair
+    pub fn private_key() -> PKey<Private> {
+        PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap()
+    }
+
+    /// Generates a self-signed certificate for `localhost` and `127.0.0.1`
+    /// with the given common name
+    pub fn self_signed_certificate(common_name: &str, key: &PKey<Private>) -> X509 {
+        let mut subject_alt_name = SubjectAlternativeName::new();
+        subject_alt_name.dns("localhost").ip("127.0.0.1");
+        certificate(&[("CN", common_name)], subject_alt_name, key)
+    }
+
+    /// Generates a self-signed certificate with the given subject name entries,
+    /// most significant first, and subject alternative names
+    pub fn certificate(
+        entries: &[(&str, &str)],
+        subject_alt_name: SubjectAlternativeName,
+        key: &PKey<Private>,
+    ) -> X509 {
+        let mut name = X509Name::builder().unwrap();
+        for (field, value) in entries {
+            name.append_entry_by_text(field, value).unwrap();
+        }
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        let serial_number = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+        builder.set_serial_number(&serial_number).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let subject_alt_name = subject_alt_name
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(subject_alt_name).unwrap();
+        builder.sign(key, MessageDigest::sha256()).unwrap();
+        builder.build()
+    }
+
+    /// Writes the given PEM contents to a uniquely named file in the temp directory
+    pub fn write_temp_file(name: &str, pem: &[u8]) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("arboric-{}-{}.pem", std::process::id(), name));
+        std::fs::write(&path, pem).unwrap();
+        path
+    }
+
+    /// Generates a self-signed certificate and key, writes them to temp files, and
+    /// returns a [TlsConfig](arboric::config::TlsConfig) pointing to them
+    pub fn self_signed_tls_config(name: &str, http2: bool) -> (TlsConfig, X509) {
+        let key = private_key();
+        let certificate = self_signed_certificate(name, &key);
+        let certificate_chain =
+            write_temp_file(&format!("{}-cert", name), &certificate.to_pem().unwrap());
+        let private_key = write_temp_file(
+            &format!("{}-key", name),
+            &key.private_key_to_pem_pkcs8().unwrap(),
+        );
+        let mut tls_config = TlsConfig::new(
+            certificate_chain.to_string_lossy(),
+            private_key.to_string_lossy(),
+        );
+        tls_config.http2 = http2;
+        (tls_config, certificate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Import names from outer (for mod tests) scope.
+    use super::*;
+
+    use super::test_support::*;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use serde_json::json;
+
+    #[test]
+    fn test_acceptor() {
+        crate::initialize_test_logging();
+        let (tls_config, _) = self_signed_tls_config("test_acceptor", true);
+        assert!(acceptor(&tls_config).is_ok());
+    }
+
+    #[test]
+    fn test_acceptor_mismatched_key() {
+        crate::initialize_test_logging();
+        let (mut tls_config, _) = self_signed_tls_config("test_acceptor_mismatched_key", false);
+        let (other, _) = self_signed_tls_config("test_acceptor_mismatched_key_other", false);
+        tls_config.private_key = other.private_key;
+        match acceptor(&tls_config) {
+            // Depending on the OpenSSL version the mismatch is reported either
+            // when the key is loaded or when it's checked
+            Err(ArboricError::OpenSslError { .. }) => (),
+            Err(err) => panic!("Expected ArboricError::OpenSslError, got {:?}!", err),
+            Ok(_) => panic!("Expected acceptor() to fail!"),
+        }
+    }
+
+    #[test]
+    fn test_certificate_claims() {
+        crate::initialize_test_logging();
+        let key = private_key();
+        let certificate = self_signed_certificate("test_certificate_claims", &key);
+        let claims = certificate_claims(&certificate, &ClientCertificateClaims::default());
+        assert_eq!(
+            "CN=test_certificate_claims",
+            claims.get("cert_sub").unwrap()
+        );
+        assert_eq!(
+            "CN=test_certificate_claims",
+            claims.get("cert_iss").unwrap()
+        );
+        assert_eq!(
+            &json!(["localhost", "127.0.0.1"]),
+            claims.get("cert_sans").unwrap()
+        );
+        assert!(!claims.contains_key("sub"));
+        assert!(!claims.contains_key("iss"));
+
+        let mapping = ClientCertificateClaims {
+            subject: "client_dn".into(),
+            subject_alt_names: "client_names".into(),
+            issuer: "client_ca".into(),
+        };
+        let claims = certificate_claims(&certificate, &mapping);
+        assert!(!claims.contains_key("cert_sub"));
+        assert_eq!(
+            "CN=test_certificate_claims",
+            claims.get("client_dn").unwrap()
+        );
+        assert_eq!(
+            &json!(["localhost", "127.0.0.1"]),
+            claims.get("client_names").unwrap()
+        );
+        assert_eq!(
+            "CN=test_certificate_claims",
+            claims.get("client_ca").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_certificate_claims_escape_special_characters() {
+        crate::initialize_test_logging();
+        let key = private_key();
+        let mut subject_alt_name = SubjectAlternativeName::new();
+        subject_alt_name
+            .dns("billing.internal")
+            .uri("spiffe://example.org/ns/a=b+c;d")
+            .email("\"o'brien\"+ops@example.org");
+        let certificate = certificate(
+            &[
+                ("C", "US"),
+                ("O", "Example, Inc."),
+                ("OU", "#ops <core>"),
+                ("CN", " a\\b+c=d;\"e\" "),
+            ],
+            subject_alt_name,
+            &key,
+        );
+        let claims = certificate_claims(&certificate, &ClientCertificateClaims::default());
+        let expected = r#"CN=\ a\\b\+c\=d\;\"e\"\ ,OU=\#ops \<core\>,O=Example\, Inc.,C=US"#;
+        assert_eq!(expected, claims.get("cert_sub").unwrap());
+        assert_eq!(expected, claims.get("cert_iss").unwrap());
+        assert_eq!(
+            &json!([
+                "billing.internal",
+                "spiffe://example.org/ns/a=b+c;d",
+                "\"o'brien\"+ops@example.org"
+            ]),
+            claims.get("cert_sans").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_escape_attribute_value() {
+        assert_eq!("example.org", escape_attribute_value("example.org"));
+        assert_eq!("a#b c", escape_attribute_value("a#b c"));
+        assert_eq!("\\#a", escape_attribute_value("#a"));
+        assert_eq!("\\ \\ ", escape_attribute_value("  "));
+        assert_eq!("\\ ", escape_attribute_value(" "));
+        assert_eq!("a\\00b", escape_attribute_value("a\0b"));
+        assert_eq!("", escape_attribute_value(""));
+    }
+
+    #[test]
+    fn test_upstream_client_requires_certificate_and_key() {
+        crate::initialize_test_logging();
+        let (tls_config, _) =
+            self_signed_tls_config("test_upstream_client_requires_certificate_and_key", false);
+        let mut upstream_tls = UpstreamTlsConfig {
+            client_certificate: Some(tls_config.certificate_chain.clone()),
+            ..Default::default()
+        };
+        assert!(upstream_client(Some(&upstream_tls)).is_err());
+        upstream_tls.client_key = Some(tls_config.private_key.clone());
+        assert!(upstream_client(Some(&upstream_tls)).is_ok());
+    }
+
+    /// Stands up a TLS listener with a self-signed certificate, and checks that
+    /// the upstream client can reach it only once it trusts that certificate
+    #[test]
+    fn test_upstream_client_ca_bundle() {
+        use crate::config::ListenerConfig;
+        use futures::Future;
+        use hyper::{Body, Method, Request, StatusCode, Uri};
+        use std::net::{IpAddr, Ipv4Addr, TcpListener};
+
+        crate::initialize_test_logging();
+        let (tls_config, _) = self_signed_tls_config("test_upstream_client_ca_bundle", false);
+        let port = {
+            let socket = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            socket.local_addr().unwrap().port()
+        };
+        let api_uri = "http://localhost:3001/graphql".parse::<Uri>().unwrap();
+        let mut listener_config =
+            ListenerConfig::ip_addr_and_port(IpAddr::V4(Ipv4Addr::LOCALHOST), port, &api_uri);
+        listener_config.tls = Some(tls_config.clone());
+        let server = crate::Listener::new(listener_config).bind().unwrap();
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.spawn(server);
+
+        let uri = format!("https://localhost:{}/", port);
+        let put = |client: &HttpsClient| {
+            let request = Request::builder()
+                .method(Method::PUT)
+                .uri(uri.as_str())
+                .body(Body::empty())
+                .unwrap();
+            client.request(request)
+        };
+
+        let untrusting = upstream_client(None).unwrap();
+        assert!(runtime.block_on(put(&untrusting)).is_err());
+
+        let upstream_tls = UpstreamTlsConfig {
+            ca_bundle: Some(tls_config.certificate_chain.clone()),
+            ..Default::default()
+        };
+        let trusting = upstream_client(Some(&upstream_tls)).unwrap();
+        let response = runtime.block_on(put(&trusting)).unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+
+        let _ = runtime.shutdown_now().wait();
+    }
+
+    #[test]
+    fn test_acceptor_missing_files() {
+        crate::initialize_test_logging();
+        let tls_config = TlsConfig::new("does/not/exist.pem", "does/not/exist.key");
+        match acceptor(&tls_config) {
+            Err(ArboricError::OpenSslError { message, .. }) => assert!(
+                message.starts_with("Unable to load certificate chain from does/not/exist.pem")
+            ),
+            Err(err) => panic!("Expected ArboricError::OpenSslError, got {:?}!", err),
+            Ok(_) => panic!("Expected acceptor() to fail!"),
+        }
+    }
+}
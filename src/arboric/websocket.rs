@@ -0,0 +1,684 @@
+//! GraphQL over WebSocket. Arboric answers the WebSocket upgrade itself, opens a
+//! WebSocket to the upstream API, then relays messages between the two. Callers are
+//! authenticated when they initialise the connection, and each operation they start
+//! is checked against the policies (and metered) just like a POST would be.
+
+use super::listener::ListenerContext;
+use super::metering::UsageEvent;
+use super::proxy_service::{self, BoxFut};
+use super::refusal::Refusal;
+use super::tls::UpstreamStream;
+use crate::ArboricError;
+use crate::Claims;
+use futures::sync::mpsc;
+use futures::{future, stream, Future, Sink, Stream};
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::upgrade::Upgraded;
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+use log::{debug, trace, warn};
+use serde_json::{json, Value};
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio_tungstenite::WebSocketStream;
+use tungstenite::handshake::client::Request as UpstreamRequest;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::frame::CloseFrame;
+use tungstenite::protocol::Role;
+use tungstenite::Message;
+
+/// Appended to the `Sec-WebSocket-Key` to compute the `Sec-WebSocket-Accept` (RFC 6455)
+static WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// How many messages each direction of a relayed connection queues before it
+/// stops reading from the other side
+const RELAY_BUFFER: usize = 16;
+
+/// The GraphQL over WebSocket subprotocols we speak
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    /// `graphql-transport-ws`, spoken by the graphql-ws library
+    GraphqlTransportWs,
+    /// `graphql-ws`, spoken by the legacy subscriptions-transport-ws library
+    SubscriptionsTransportWs,
+}
+
+impl Protocol {
+    /// The subprotocol name, as given in the `Sec-WebSocket-Protocol` header
+    pub fn name(self) -> &'static str {
+        match self {
+            Protocol::GraphqlTransportWs => "graphql-transport-ws",
+            Protocol::SubscriptionsTransportWs => "graphql-ws",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Protocol> {
+        match name {
+            "graphql-transport-ws" => Some(Protocol::GraphqlTransportWs),
+            "graphql-ws" => Some(Protocol::SubscriptionsTransportWs),
+            _ => None,
+        }
+    }
+
+    /// The first subprotocol offered in the `Sec-WebSocket-Protocol` header that we
+    /// speak, if any
+    pub fn negotiate(headers: &HeaderMap) -> Option<Protocol> {
+        headers
+            .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|name| Protocol::from_name(name.trim()))
+            .next()
+    }
+
+    /// The type of the message that starts an operation
+    fn start_type(self) -> &'static str {
+        match self {
+            Protocol::GraphqlTransportWs => "subscribe",
+            Protocol::SubscriptionsTransportWs => "start",
+        }
+    }
+
+    /// The messages that answer an operation that was refused
+    fn operation_refused(self, id: &Value, errors: Vec<Value>) -> Vec<Message> {
+        match self {
+            Protocol::GraphqlTransportWs => vec![text(json!({
+                "id": id,
+                "type": "error",
+                "payload": errors,
+            }))],
+            // subscriptions-transport-ws servers give the errors of an operation
+            // that fails validation as its result, then complete it
+            Protocol::SubscriptionsTransportWs => vec![
+                text(json!({
+                    "id": id,
+                    "type": "data",
+                    "payload": { "data": null, "errors": errors },
+                })),
+                text(json!({ "id": id, "type": "complete" })),
+            ],
+        }
+    }
+
+    /// The messages that refuse to initialise the connection, closing it
+    fn connection_refused(self, refusal: &Refusal, errors: Vec<Value>) -> Vec<Message> {
+        match self {
+            Protocol::GraphqlTransportWs => vec![close(4403, &refusal.message())],
+            Protocol::SubscriptionsTransportWs => vec![
+                text(json!({
+                    "type": "connection_error",
+                    "payload": errors.into_iter().next().unwrap_or(Value::Null),
+                })),
+                close(CloseCode::Policy.into(), &refusal.message()),
+            ],
+        }
+    }
+
+    /// The messages that refuse an operation started before the connection was
+    /// initialised, closing it
+    fn not_initialised(self, refusal: &Refusal, errors: Vec<Value>) -> Vec<Message> {
+        match self {
+            Protocol::GraphqlTransportWs => vec![close(4401, "Unauthorized")],
+            Protocol::SubscriptionsTransportWs => self.connection_refused(refusal, errors),
+        }
+    }
+
+    /// What to do with a `connection_init` once the connection's been initialised
+    fn already_initialised(self) -> Action {
+        match self {
+            Protocol::GraphqlTransportWs => {
+                Action::Close(vec![close(4429, "Too many initialisation requests")])
+            }
+            // subscriptions-transport-ws has no error for this, so it's just dropped
+            Protocol::SubscriptionsTransportWs => Action::Reply(vec![]),
+        }
+    }
+}
+
+/// Whether the request asks to be upgraded to a WebSocket
+pub fn is_upgrade(req: &Request<Body>) -> bool {
+    req.method() == Method::GET
+        && has_token(req.headers(), header::UPGRADE, "websocket")
+        && has_token(req.headers(), header::CONNECTION, "upgrade")
+        && req.headers().contains_key(header::SEC_WEBSOCKET_KEY)
+}
+
+/// Whether the comma separated values of the given header include the given token
+fn has_token(headers: &HeaderMap, name: HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+/// Answers the upgrade request, then once the connection's been upgraded, relays
+/// messages between the caller and the upstream API's WebSocket. The claims are
+/// those from the upgrade request, if it had a bearer token or client certificate.
+pub fn upgrade(
+    context: Arc<ListenerContext>,
+    claims: Option<Claims>,
+    req: Request<Body>,
+) -> BoxFut {
+    trace!("upgrade({:?})", &req);
+    let protocol = match Protocol::negotiate(req.headers()) {
+        Some(protocol) => protocol,
+        None => {
+            let offered = req
+                .headers()
+                .get(header::SEC_WEBSOCKET_PROTOCOL)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            return proxy_service::refuse(&context, Refusal::UnsupportedProtocol(offered));
+        }
+    };
+    debug!("WebSocket subprotocol => {}", protocol.name());
+    let accept = accept_key(req.headers()[header::SEC_WEBSOCKET_KEY].as_bytes());
+    let authorization = req.headers().get(header::AUTHORIZATION).cloned();
+    let session = Session::new(context.clone(), protocol, claims);
+
+    let task = req
+        .into_body()
+        .on_upgrade()
+        .map_err(|e| warn!("WebSocket upgrade failed: {}", e))
+        .and_then(move |upgraded| {
+            let client = WebSocketStream::from_raw_socket(upgraded, Role::Server, None);
+            connect_upstream(&context, protocol, authorization).then(move |result| {
+                let relaying: Box<dyn Future<Item = (), Error = ()> + Send> = match result {
+                    Ok(upstream) => Box::new(relay(session, client, upstream)),
+                    Err(err) => {
+                        warn!("{}", err);
+                        let closing = client
+                            .send(close(
+                                CloseCode::Error.into(),
+                                "Unable to connect to the upstream API",
+                            ))
+                            .map(|_| ())
+                            .map_err(|e| debug!("WebSocket: {}", e));
+                        Box::new(closing)
+                    }
+                };
+                relaying
+            })
+        });
+    hyper::rt::spawn(task);
+
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+    let headers = response.headers_mut();
+    headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+    headers.insert(header::CONNECTION, HeaderValue::from_static("Upgrade"));
+    headers.insert(
+        header::SEC_WEBSOCKET_PROTOCOL,
+        HeaderValue::from_static(protocol.name()),
+    );
+    if let Ok(accept) = HeaderValue::from_str(&accept) {
+        headers.insert(header::SEC_WEBSOCKET_ACCEPT, accept);
+    }
+    Box::new(future::ok(response))
+}
+
+/// Computes the `Sec-WebSocket-Accept` for the given `Sec-WebSocket-Key`
+fn accept_key(key: &[u8]) -> String {
+    let mut input = key.to_vec();
+    input.extend_from_slice(WEBSOCKET_GUID.as_bytes());
+    base64::encode(&openssl::sha::sha1(&input))
+}
+
+/// Opens a WebSocket to the upstream API, speaking the same subprotocol as the
+/// caller, and passing on the caller's `Authorization` header, if any
+fn connect_upstream(
+    context: &ListenerContext,
+    protocol: Protocol,
+    authorization: Option<HeaderValue>,
+) -> impl Future<Item = WebSocketStream<UpstreamStream>, Error = ArboricError> + Send {
+    let api_uri = context.api_uri.clone();
+    let connecting = context.upstream_connector.connect(&api_uri);
+    future::result(upstream_request(&api_uri, protocol, authorization.as_ref())).and_then(
+        move |request| {
+            connecting.and_then(move |stream| {
+                tokio_tungstenite::client_async(request, stream)
+                    .map(|(upstream, _)| upstream)
+                    .map_err(move |e| {
+                        ArboricError::general(format!(
+                            "WebSocket handshake with {} failed: {}",
+                            api_uri, e
+                        ))
+                    })
+            })
+        },
+    )
+}
+
+/// The handshake request for the upstream API's WebSocket, which is at the upstream
+/// API URI with a `ws://` (or for `https://`, a `wss://`) scheme
+fn upstream_request(
+    api_uri: &Uri,
+    protocol: Protocol,
+    authorization: Option<&HeaderValue>,
+) -> crate::Result<UpstreamRequest<'static>> {
+    let scheme = match api_uri.scheme_str() {
+        Some("https") => "wss",
+        _ => "ws",
+    };
+    let authority = api_uri
+        .authority_part()
+        .map_or("", |authority| authority.as_str());
+    let path_and_query = api_uri
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str());
+    let url = url::Url::parse(&format!("{}://{}{}", scheme, authority, path_and_query))
+        .map_err(|e| ArboricError::general(format!("Invalid upstream WebSocket URL: {}", e)))?;
+    let mut extra_headers = vec![(
+        Cow::from("Sec-WebSocket-Protocol"),
+        Cow::from(protocol.name()),
+    )];
+    if let Some(authorization) = authorization.and_then(|value| value.to_str().ok()) {
+        extra_headers.push((
+            Cow::from("Authorization"),
+            Cow::from(authorization.to_string()),
+        ));
+    }
+    Ok(UpstreamRequest {
+        url,
+        extra_headers: Some(extra_headers),
+    })
+}
+
+/// Relays messages between the caller and the upstream API until either closes the
+/// connection. Messages from the caller go through the [Session](Session) first.
+fn relay(
+    mut session: Session,
+    client: WebSocketStream<Upgraded>,
+    upstream: WebSocketStream<UpstreamStream>,
+) -> impl Future<Item = (), Error = ()> + Send {
+    let (client_sink, client_stream) = client.split();
+    let (upstream_sink, upstream_stream) = upstream.split();
+    let (to_client, to_client_rx) = mpsc::channel::<Message>(RELAY_BUFFER);
+    let (to_upstream, to_upstream_rx) = mpsc::channel::<Message>(RELAY_BUFFER);
+
+    // Each direction is written by its own task, which finishes (and closes its
+    // sink) once everything that sends to it has finished
+    hyper::rt::spawn(
+        client_sink
+            .sink_map_err(|e| debug!("WebSocket: {}", e))
+            .send_all(to_client_rx)
+            .map(|_| ()),
+    );
+    hyper::rt::spawn(
+        upstream_sink
+            .sink_map_err(|e| debug!("Upstream WebSocket: {}", e))
+            .send_all(to_upstream_rx)
+            .map(|_| ()),
+    );
+    hyper::rt::spawn(
+        upstream_stream
+            .map_err(|e| debug!("Upstream WebSocket: {}", e))
+            .forward(to_client.clone().sink_map_err(|_| ()))
+            .map(|_| ()),
+    );
+
+    // The channels are bounded, so the next message isn't read from the caller
+    // until the last one has been queued, and a slow reader on either side holds
+    // back its writer rather than buffering without limit
+    client_stream
+        .map_err(|e| debug!("WebSocket: {}", e))
+        .fold(
+            (to_upstream, to_client),
+            move |(to_upstream, to_client), message| {
+                let sending: Box<dyn Future<Item = _, Error = ()> + Send> =
+                    match session.handle(message) {
+                        Action::Forward(message) => Box::new(
+                            to_upstream
+                                .send(message)
+                                .map(|to_upstream| (to_upstream, to_client))
+                                .map_err(|_| ()),
+                        ),
+                        Action::Reply(messages) => Box::new(
+                            to_client
+                                .send_all(stream::iter_ok(messages))
+                                .map(|(to_client, _)| (to_upstream, to_client))
+                                .map_err(|_| ()),
+                        ),
+                        Action::Close(messages) => Box::new(
+                            to_client
+                                .send_all(stream::iter_ok(messages))
+                                .then(|_| Err(())),
+                        ),
+                    };
+                sending
+            },
+        )
+        .then(|_| Ok(()))
+}
+
+/// What to do with a message from the caller
+#[derive(Debug, PartialEq)]
+enum Action {
+    /// Relay it to the upstream API
+    Forward(Message),
+    /// Don't relay it, but answer the caller with these messages
+    Reply(Vec<Message>),
+    /// Answer the caller with these messages, then stop relaying
+    Close(Vec<Message>),
+}
+
+/// A caller's WebSocket connection: the subprotocol it speaks, whether it's been
+/// initialised, and the claims its operations are authorized with
+#[derive(Debug)]
+struct Session {
+    context: Arc<ListenerContext>,
+    protocol: Protocol,
+    claims: Option<Claims>,
+    initialised: bool,
+}
+
+impl Session {
+    fn new(context: Arc<ListenerContext>, protocol: Protocol, claims: Option<Claims>) -> Self {
+        Session {
+            context,
+            protocol,
+            claims,
+            initialised: false,
+        }
+    }
+
+    /// Decides what to do with a message from the caller. Only `connection_init`
+    /// and messages that start an operation are looked at, anything else is relayed
+    /// as is.
+    fn handle(&mut self, message: Message) -> Action {
+        trace!("handle({:?})", &message);
+        // Servers read binary frames as text too, so they get the same scrutiny
+        let json: Value = match message {
+            Message::Text(ref text) => serde_json::from_str(text),
+            Message::Binary(ref bytes) => serde_json::from_slice(bytes),
+            _ => return Action::Forward(message),
+        }
+        .unwrap_or(Value::Null);
+        let verbosity = self.context.error_verbosity;
+        match json.get("type").and_then(Value::as_str) {
+            Some("connection_init") if !self.initialised => {
+                match self.authenticate(json.get("payload")) {
+                    Ok(claims) => {
+                        debug!("WebSocket initialised, claims => {:?}", &claims);
+                        self.claims = claims;
+                        self.initialised = true;
+                        Action::Forward(message)
+                    }
+                    Err(refusal) => {
+                        proxy_service::log_refusal(&self.context, &refusal);
+                        let errors = refusal.errors(verbosity);
+                        Action::Close(self.protocol.connection_refused(&refusal, errors))
+                    }
+                }
+            }
+            Some(message_type) if message_type == self.protocol.start_type() => {
+                if !self.initialised {
+                    let refusal = Refusal::MissingToken;
+                    proxy_service::log_refusal(&self.context, &refusal);
+                    let errors = refusal.errors(verbosity);
+                    return Action::Close(self.protocol.not_initialised(&refusal, errors));
+                }
+                match self.authorize(json.get("payload")) {
+                    Ok(usage) => {
+                        // Subscriptions are long lived, so they're metered as they start
+                        proxy_service::meter(&self.context, &usage);
+                        Action::Forward(message)
+                    }
+                    Err(refusal) => {
+                        proxy_service::log_refusal(&self.context, &refusal);
+                        let errors = refusal.errors(verbosity);
+                        Action::Reply(self.protocol.operation_refused(&json["id"], errors))
+                    }
+                }
+            }
+            Some("connection_init") => self.protocol.already_initialised(),
+            _ => Action::Forward(message),
+        }
+    }
+
+    /// Authenticates the caller when the connection is initialised. Unless the
+    /// upgrade request had credentials, the bearer token is taken from the
+    /// `connection_init` payload, if it has one.
+    fn authenticate(&mut self, payload: Option<&Value>) -> Result<Option<Claims>, Refusal> {
+        let context = self.context.clone();
+        let claims = match (self.claims.take(), &context.jwt_keys) {
+            (Some(claims), _) => Some(claims),
+            (None, Some(jwt_keys)) => match payload.and_then(connection_token) {
+                Some(token) => Some(
+                    proxy_service::verify_token(&token, jwt_keys, &context.jwt_validation)
+                        .map_err(Refusal::InvalidToken)?,
+                ),
+                None => None,
+            },
+            (None, None) => None,
+        };
+        match claims {
+            None if context.anonymous => {
+                debug!("Anonymous WebSocket");
+                Ok(Some(Claims::new()))
+            }
+            None if context.authenticates() => Err(Refusal::MissingToken),
+            claims => Ok(claims),
+        }
+    }
+
+    /// Parses, validates, meters and authorizes the operation in the payload of a
+    /// message that starts one
+    fn authorize(&self, payload: Option<&Value>) -> Result<UsageEvent, Refusal> {
+        let started = Instant::now();
+        let body = payload.map_or_else(String::new, Value::to_string);
+        match super::count_json_query(&body) {
+            Ok(Some((document, counts, variables))) => proxy_service::authorize(
+                &self.context,
+                self.claims.clone(),
+                document,
+                &variables,
+                counts,
+                started,
+            )
+            .map(|mut usage| {
+                usage.latency = started.elapsed();
+                usage
+            }),
+            Ok(None) => Err(Refusal::ParseFailed("No query".into())),
+            Err(err) => Err(proxy_service::parse_failed(err)),
+        }
+    }
+}
+
+/// The bearer token in a `connection_init` payload. Clients put it in different
+/// places, e.g. `{"Authorization": "Bearer ..."}`, `{"headers": {"Authorization":
+/// "Bearer ..."}}` or `{"authToken": "..."}`, with or without the `Bearer` prefix.
+fn connection_token(payload: &Value) -> Option<String> {
+    let headers = payload.get("headers").unwrap_or(&Value::Null);
+    ["Authorization", "authorization", "authToken", "token"]
+        .iter()
+        .filter_map(|key| payload.get(key).or_else(|| headers.get(key)))
+        .filter_map(Value::as_str)
+        .map(|value| value.strip_prefix("Bearer ").unwrap_or(value).to_string())
+        .next()
+}
+
+fn text(json: Value) -> Message {
+    Message::Text(json.to_string())
+}
+
+fn close(code: u16, reason: &str) -> Message {
+    Message::Close(Some(CloseFrame {
+        code: CloseCode::from(code),
+        reason: Cow::Owned(reason.to_string()),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    // Import names from outer (for mod tests) scope.
+    use super::*;
+
+    use crate::arboric::proxy_service::test_support::*;
+
+    fn new_session(protocol: Protocol) -> Session {
+        let api_uri = "http://localhost:3001/graphql".parse::<Uri>().unwrap();
+        let listener = crate::Listener::new(anonymous_listener_config(&api_uri));
+        Session::new(listener.context(), protocol, None)
+    }
+
+    fn message(json: Value) -> Message {
+        Message::Text(json.to_string())
+    }
+
+    fn subscribe(protocol: Protocol, query: &str) -> Message {
+        message(json!({
+            "id": "1",
+            "type": protocol.start_type(),
+            "payload": { "query": query },
+        }))
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // From RFC 6455, section 1.3
+        assert_eq!(
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=",
+            accept_key(b"dGhlIHNhbXBsZSBub25jZQ==")
+        );
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(None, Protocol::negotiate(&headers));
+        headers.insert(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static("mqtt, graphql-ws"),
+        );
+        assert_eq!(
+            Some(Protocol::SubscriptionsTransportWs),
+            Protocol::negotiate(&headers)
+        );
+        headers.insert(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static("graphql-transport-ws, graphql-ws"),
+        );
+        assert_eq!(
+            Some(Protocol::GraphqlTransportWs),
+            Protocol::negotiate(&headers)
+        );
+    }
+
+    #[test]
+    fn test_is_upgrade() {
+        let upgrade = Request::get("/graphql")
+            .header(header::UPGRADE, "websocket")
+            .header(header::CONNECTION, "keep-alive, Upgrade")
+            .header(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+            .body(Body::empty())
+            .unwrap();
+        assert!(is_upgrade(&upgrade));
+        let get = Request::get("/graphql?query={hero{name}}")
+            .body(Body::empty())
+            .unwrap();
+        assert!(!is_upgrade(&get));
+    }
+
+    #[test]
+    fn test_connection_token() {
+        assert_eq!(
+            Some("abc".to_string()),
+            connection_token(&json!({"Authorization": "Bearer abc"}))
+        );
+        assert_eq!(
+            Some("abc".to_string()),
+            connection_token(&json!({"headers": {"authorization": "Bearer abc"}}))
+        );
+        assert_eq!(
+            Some("abc".to_string()),
+            connection_token(&json!({"authToken": "abc"}))
+        );
+        assert_eq!(None, connection_token(&json!({})));
+    }
+
+    #[test]
+    fn test_session_authenticates_connection_init() {
+        crate::initialize_test_logging();
+        let protocol = Protocol::GraphqlTransportWs;
+        let mut session = new_session(protocol);
+
+        let early = session.handle(subscribe(protocol, "{publicPosts{title}}"));
+        assert_eq!(Action::Close(vec![close(4401, "Unauthorized")]), early);
+
+        let init = message(json!({
+            "type": "connection_init",
+            "payload": { "Authorization": format!("Bearer {}", TOKEN) },
+        }));
+        assert_eq!(Action::Forward(init.clone()), session.handle(init));
+        let secrets = subscribe(protocol, "subscription { secrets { value } }");
+        assert_eq!(Action::Forward(secrets.clone()), session.handle(secrets));
+
+        let again = message(json!({ "type": "connection_init" }));
+        assert_eq!(
+            Action::Close(vec![close(4429, "Too many initialisation requests")]),
+            session.handle(again)
+        );
+
+        let mut session = new_session(protocol);
+        let invalid = message(json!({
+            "type": "connection_init",
+            "payload": { "authToken": format!("{}x", TOKEN) },
+        }));
+        match session.handle(invalid) {
+            Action::Close(messages) => assert_eq!(1, messages.len()),
+            action => panic!("Expected Action::Close, got {:?}!", action),
+        }
+    }
+
+    #[test]
+    fn test_session_authorizes_operations() {
+        crate::initialize_test_logging();
+        let protocol = Protocol::GraphqlTransportWs;
+        let mut session = new_session(protocol);
+
+        let init = message(json!({ "type": "connection_init" }));
+        assert_eq!(Action::Forward(init.clone()), session.handle(init));
+
+        let public = subscribe(protocol, "{publicPosts{title}}");
+        assert_eq!(Action::Forward(public.clone()), session.handle(public));
+
+        let secrets = session.handle(subscribe(protocol, "subscription { secrets { value } }"));
+        assert_eq!(
+            Action::Reply(vec![message(json!({
+                "id": "1",
+                "type": "error",
+                "payload": [{"message": "Denied by policy", "extensions": {"code": "FORBIDDEN"}}],
+            }))]),
+            secrets
+        );
+
+        let complete = message(json!({ "id": "1", "type": "complete" }));
+        assert_eq!(Action::Forward(complete.clone()), session.handle(complete));
+    }
+
+    #[test]
+    fn test_session_subscriptions_transport_ws() {
+        crate::initialize_test_logging();
+        let protocol = Protocol::SubscriptionsTransportWs;
+        let mut session = new_session(protocol);
+
+        let init = message(json!({ "type": "connection_init", "payload": {} }));
+        assert_eq!(Action::Forward(init.clone()), session.handle(init));
+        let again = message(json!({ "type": "connection_init", "payload": {} }));
+        assert_eq!(Action::Reply(vec![]), session.handle(again));
+
+        match session.handle(subscribe(protocol, "{publicPosts{title}")) {
+            Action::Reply(messages) => {
+                assert_eq!(2, messages.len());
+                assert_eq!(message(json!({"id": "1", "type": "complete"})), messages[1]);
+            }
+            action => panic!("Expected Action::Reply, got {:?}!", action),
+        }
+    }
+}
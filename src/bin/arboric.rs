@@ -5,7 +5,8 @@ extern crate hyper;
 extern crate clap;
 
 use failure::Error;
-use log::{debug, trace};
+use futures::{future, Future, Stream};
+use log::{debug, error, info, trace};
 
 use clap::{App, Arg, SubCommand};
 
@@ -33,20 +34,102 @@ fn main() -> Result<(), Error> {
 
     let config = arboric::config::yaml::read_yaml_configuration(config_file)?;
 
-    run(config);
-    Ok(())
+    run(config)
 }
 
-/// Run the Arboric proxy server according to the given configuration
-pub fn run(config: arboric::Configuration) {
+/// Run the Arboric proxy server according to the given configuration. All
+/// configured listeners are bound up front, and those that bind successfully are
+/// served on a single runtime. A listener that fails to bind is reported and skipped.
+/// If metrics are configured, the registry the listeners that log to `prometheus`
+/// share is served by its own listener.
+pub fn run(config: arboric::Configuration) -> Result<(), Error> {
     arboric::initialize_logging(&config);
 
-    if let Some(listener_config) = config.listeners.first() {
-        let proxy = arboric::Listener::new(listener_config.clone());
+    if config.listeners.is_empty() {
+        panic!("No listeners configured! See arboric::Configuration::listener()")
+    }
+
+    let mut servers = Vec::new();
+    for listener_config in config.listeners.into_iter() {
+        let listener_address = listener_config.listener_address;
+        let proxy = match arboric::Listener::try_new(listener_config) {
+            Ok(proxy) => proxy,
+            Err(err) => {
+                error!(
+                    "Unable to configure listener on {}: {}",
+                    listener_address, err
+                );
+                continue;
+            }
+        };
         trace!("{:?}", proxy);
 
-        proxy.run();
-    } else {
-        panic!("No listeners configured! See arboric::Configuration::listener()")
+        match proxy.bind() {
+            Ok(server) => servers.push(server),
+            Err(err) => error!("{}", err),
+        }
+    }
+
+    if servers.is_empty() {
+        return Err(failure::err_msg(
+            "None of the configured listeners could be started!",
+        ));
     }
+
+    if let Some(metrics_config) = config.arboric.metrics {
+        match metrics_config
+            .metrics
+            .serve(metrics_config.listener_address)
+        {
+            Ok(server) => servers.push(server),
+            Err(err) => error!("{}", err),
+        }
+    }
+
+    // Run all the servers until we're told to stop, then flush what the sinks have
+    // queued before exiting
+    arboric::serve_until(servers, shutdown_signal())?;
+    Ok(())
+}
+
+/// Completes when the process receives SIGINT (Ctrl+C) or, on Unix, SIGTERM. Never
+/// completes if it can't listen for them.
+fn shutdown_signal() -> impl Future<Item = (), Error = ()> + Send {
+    future::lazy(|| {
+        let ctrl_c = tokio_signal::ctrl_c()
+            .flatten_stream()
+            .into_future()
+            .map(|_| "SIGINT")
+            .map_err(|(e, _)| e);
+        #[cfg(unix)]
+        let signals: Box<dyn Future<Item = &str, Error = std::io::Error> + Send> = {
+            use tokio_signal::unix::{Signal, SIGTERM};
+            let terminate = Signal::new(SIGTERM)
+                .flatten_stream()
+                .into_future()
+                .map(|_| "SIGTERM")
+                .map_err(|(e, _)| e);
+            Box::new(
+                ctrl_c
+                    .select(terminate)
+                    .map(|(name, _)| name)
+                    .map_err(|(e, _)| e),
+            )
+        };
+        #[cfg(not(unix))]
+        let signals = ctrl_c;
+        signals.then(|result| -> Box<dyn Future<Item = (), Error = ()> + Send> {
+            match result {
+                Ok(name) => {
+                    info!("Received {}", name);
+                    Box::new(future::ok(()))
+                }
+                // Keep serving, just without a way to shut down gracefully
+                Err(e) => {
+                    error!("Unable to listen for signals: {}", e);
+                    Box::new(future::empty())
+                }
+            }
+        })
+    })
 }